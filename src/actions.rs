@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use bevy::ecs::system::SystemParam;
+use bevy::input::InputSystem;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter, IntoEnumIterator};
+
+use crate::GameState::{Game, MainMenu};
+
+pub const KEY_BINDINGS_PATH: &str = "config/keybindings.ron";
+
+// Everything the player can do from the keyboard. Systems ask for actions rather than keys,
+// so the keys behind them can be rebound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, EnumIter, Display, Serialize, Deserialize)]
+pub enum Action {
+    PanUp,
+    PanDown,
+    PanLeft,
+    PanRight,
+    ZoomIn,
+    ZoomOut,
+    LayerUp,
+    LayerDown,
+    Pause,
+    Step,
+    SpeedUp,
+    SlowDown,
+}
+impl Action {
+    pub fn default_key(self: &Self) -> KeyCode {
+        match self {
+            Action::PanUp => KeyCode::W,
+            Action::PanDown => KeyCode::S,
+            Action::PanLeft => KeyCode::A,
+            Action::PanRight => KeyCode::D,
+            Action::ZoomIn => KeyCode::E,
+            Action::ZoomOut => KeyCode::Q,
+            Action::LayerUp => KeyCode::Z,
+            Action::LayerDown => KeyCode::X,
+            Action::Pause => KeyCode::Space,
+            Action::Step => KeyCode::Period,
+            Action::SpeedUp => KeyCode::Equals,
+            Action::SlowDown => KeyCode::Minus,
+        }
+    }
+}
+
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct KeyBindings {
+    keys: BTreeMap<Action, KeyCode>,
+}
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            keys: Action::iter().map(|action| (action, action.default_key())).collect(),
+        }
+    }
+}
+impl KeyBindings {
+    // Reads the bindings file, falling back to the defaults for a missing file and for any action it leaves out.
+    pub fn load(path: &Path) -> Self {
+        let mut bindings = Self::default();
+        if let Ok(contents) = fs::read_to_string(path) {
+            match ron::from_str::<KeyBindings>(&contents) {
+                Ok(loaded) => bindings.keys.extend(loaded.keys),
+                Err(error) => warn!("Ignoring {}: {}", path.display(), error),
+            }
+        }
+        bindings
+    }
+    pub fn save(self: &Self, path: &Path) -> Result<(), String> {
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).map_err(|error| error.to_string())?;
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).map_err(|error| error.to_string())?;
+        }
+        fs::write(path, contents).map_err(|error| error.to_string())
+    }
+    pub fn key(self: &Self, action: Action) -> KeyCode {
+        self.keys.get(&action).copied().unwrap_or_else(|| action.default_key())
+    }
+    pub fn bind(self: &mut Self, action: Action, key: KeyCode) {
+        self.keys.insert(action, key);
+    }
+}
+
+// The keyboard read through the key bindings. While an egui text field has focus the keys typed into it
+// are not actions.
+#[derive(SystemParam)]
+pub struct ActionInput<'w, 's> {
+    keyboard_input: Res<'w, Input<KeyCode>>,
+    bindings: Res<'w, KeyBindings>,
+    egui_context: ResMut<'w, EguiContext>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+impl<'w, 's> ActionInput<'w, 's> {
+    pub fn pressed(self: &mut Self, action: Action) -> bool {
+        !self.typing() && self.keyboard_input.pressed(self.bindings.key(action))
+    }
+    pub fn just_pressed(self: &mut Self, action: Action) -> bool {
+        !self.typing() && self.keyboard_input.just_pressed(self.bindings.key(action))
+    }
+    fn typing(self: &mut Self) -> bool {
+        self.egui_context.ctx_mut().wants_keyboard_input()
+    }
+}
+
+// The action waiting for its new key, picked in the key bindings window.
+#[derive(Resource, Default)]
+struct PendingRebind {
+    action: Option<Action>,
+}
+
+pub struct ActionsPlugin;
+
+impl Plugin for ActionsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(KeyBindings::load(Path::new(KEY_BINDINGS_PATH)))
+        .init_resource::<PendingRebind>()
+        .init_resource::<KeyBindingsStatus>()
+        // Runs before Update so the key taken for a binding never reaches the systems acting on it.
+        .add_system_to_stage(CoreStage::PreUpdate, capture_rebind_key.after(InputSystem))
+        .add_system_set(
+            SystemSet::on_update(MainMenu)
+                .with_system(key_bindings_window)
+        )
+        .add_system_set(
+            SystemSet::on_update(Game)
+                .with_system(key_bindings_window)
+        );
+    }
+}
+
+#[derive(Resource, Default)]
+struct KeyBindingsStatus {
+    message: String,
+}
+impl KeyBindingsStatus {
+    fn save(self: &mut Self, bindings: &KeyBindings) {
+        self.message = match bindings.save(Path::new(KEY_BINDINGS_PATH)) {
+            Ok(()) => String::new(),
+            Err(error) => format!("Could not save key bindings: {}", error),
+        };
+    }
+}
+
+// The next key pressed after picking an action becomes its binding, Escape cancels.
+fn capture_rebind_key(
+    mut keyboard_input: ResMut<Input<KeyCode>>,
+    mut bindings: ResMut<KeyBindings>,
+    mut pending: ResMut<PendingRebind>,
+    mut status: ResMut<KeyBindingsStatus>)
+    {
+    let action = match pending.action {
+        Some(action) => action,
+        None => return,
+    };
+    let key = match keyboard_input.get_just_pressed().next() {
+        Some(key) => *key,
+        None => return,
+    };
+    keyboard_input.reset(key);
+    if key != KeyCode::Escape {
+        bindings.bind(action, key);
+        status.save(&bindings);
+    }
+    pending.action = None;
+}
+
+fn key_bindings_window(
+    mut egui_context: ResMut<EguiContext>,
+    mut bindings: ResMut<KeyBindings>,
+    mut pending: ResMut<PendingRebind>,
+    mut status: ResMut<KeyBindingsStatus>)
+    {
+    egui::Window::new("Key bindings").show(egui_context.ctx_mut(), |ui| {
+        egui::Grid::new("key_bindings_grid").num_columns(2).spacing([40.0, 4.0]).show(ui, |ui| {
+            for action in Action::iter() {
+                ui.label(action.to_string());
+                let label = if pending.action == Some(action) {
+                    String::from("Press a key...")
+                } else {
+                    format!("{:?}", bindings.key(action))
+                };
+                if ui.button(label).clicked() {
+                    pending.action = Some(action);
+                }
+                ui.end_row();
+            }
+        });
+        if ui.button("Reset to defaults").clicked() {
+            *bindings = KeyBindings::default();
+            pending.action = None;
+            status.save(&bindings);
+        }
+        if !status.message.is_empty() {
+            ui.label(status.message.as_str());
+        }
+    });
+}
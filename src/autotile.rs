@@ -0,0 +1,216 @@
+use bevy::prelude::Image;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy_ecs_tilemap::tiles::TileFlip;
+
+use crate::map_gen::Voxel;
+use crate::materials::{MaterialId, MaterialRegistry};
+use crate::tiles::GameTilePos;
+use crate::world3d::World3D;
+
+// Neighbour bits of an autotile mask. A corner only counts when neither side next to it does, since the sides
+// already cover it, which leaves 47 distinct masks counting the empty one.
+pub const NORTH: u8 = 1;
+pub const EAST: u8 = 2;
+pub const SOUTH: u8 = 4;
+pub const WEST: u8 = 8;
+pub const NORTH_EAST: u8 = 16;
+pub const SOUTH_EAST: u8 = 32;
+pub const SOUTH_WEST: u8 = 64;
+pub const NORTH_WEST: u8 = 128;
+const NEIGHBOURS: [(u8, i64, i64); 8] = [
+    (NORTH, 0, 1),
+    (EAST, 1, 0),
+    (SOUTH, 0, -1),
+    (WEST, -1, 0),
+    (NORTH_EAST, 1, 1),
+    (SOUTH_EAST, 1, -1),
+    (SOUTH_WEST, -1, -1),
+    (NORTH_WEST, -1, 1),
+];
+// How far into a tile an overlay reaches from a masked edge, as a fraction of the tile.
+const FRINGE_WIDTH: f32 = 0.3;
+
+pub fn canonical(mask: u8) -> u8 {
+    let mut mask = mask;
+    for (corner, side_a, side_b) in [(NORTH_EAST, NORTH, EAST), (SOUTH_EAST, SOUTH, EAST), (SOUTH_WEST, SOUTH, WEST), (NORTH_WEST, NORTH, WEST)] {
+        if mask & (side_a | side_b) != 0 {
+            mask &= !corner;
+        }
+    }
+    mask
+}
+
+// Every mask an overlay can be drawn with, leaving out the empty one.
+pub fn canonical_masks() -> Vec<u8> {
+    (1..=u8::MAX).filter(|mask| canonical(*mask) == *mask).collect()
+}
+
+// The neighbours on the same layer, leaving out those past the edge of the world.
+fn neighbours(world: &World3D, position: GameTilePos) -> impl Iterator<Item = (u8, GameTilePos)> + '_ {
+    NEIGHBOURS.iter().filter_map(move |(bit, dx, dy)| {
+        let (x, y) = (position.x as i64 + dx, position.y as i64 + dy);
+        if x < 0 || y < 0 {
+            return None;
+        }
+        let neighbour = GameTilePos { x: x as usize, y: y as usize, z: position.z };
+        world.contains(neighbour).then_some((*bit, neighbour))
+    })
+}
+
+pub fn neighbour_mask(world: &World3D, position: GameTilePos, test: impl Fn(Voxel) -> bool) -> u8 {
+    let mask = neighbours(world, position)
+        .filter(|(_, neighbour)| test(world.get(*neighbour)))
+        .fold(0, |mask, (bit, _)| mask | bit);
+    canonical(mask)
+}
+
+// The material bleeding onto a tile from its neighbours, which is the highest blend priority next to it that
+// outranks the tile's own, along with the mask of the neighbours made of it.
+pub fn blend_overlay(world: &World3D, registry: &MaterialRegistry, position: GameTilePos, material: MaterialId) -> Option<(MaterialId, u8)> {
+    let own_priority = registry.get(material).blend_priority;
+    let bleeding = neighbours(world, position)
+        .filter_map(|(_, neighbour)| world.get(neighbour))
+        .filter(|neighbour| registry.get(*neighbour).blend_priority > own_priority)
+        .max_by_key(|neighbour| registry.get(*neighbour).blend_priority)?;
+    Some((bleeding, neighbour_mask(world, position, |voxel| voxel == Some(bleeding))))
+}
+
+// A stable pseudo random number for a tile, so its variant stays the same every time it is drawn.
+pub fn position_seed(position: GameTilePos) -> u32 {
+    let mut hash = (position.x as u32).wrapping_mul(0x8da6_b343)
+        ^ (position.y as u32).wrapping_mul(0xd816_3841)
+        ^ (position.z as u32).wrapping_mul(0xcb1a_b31f);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x2c1b_3c6d);
+    hash ^= hash >> 12;
+    hash
+}
+
+// Uses the top bits of the seed, the low ones already pick the variant.
+pub fn seed_flip(seed: u32) -> TileFlip {
+    let bits = seed >> 29;
+    TileFlip { x: bits & 1 != 0, y: bits & 2 != 0, d: bits & 4 != 0 }
+}
+
+// White, opaque along the masked edges and corners and fading out towards the middle, so tiles can tint it.
+pub fn mask_image(mask: u8, size: u32) -> Image {
+    let mut data = Vec::with_capacity((size * size * 4) as usize);
+    for row in 0..size {
+        for column in 0..size {
+            // u runs east and v north across the tile, image rows run south.
+            let u = (column as f32 + 0.5) / size as f32;
+            let v = 1.0 - (row as f32 + 0.5) / size as f32;
+            let distances = [
+                (NORTH, 1.0 - v),
+                (EAST, 1.0 - u),
+                (SOUTH, v),
+                (WEST, u),
+                (NORTH_EAST, (1.0 - u).hypot(1.0 - v)),
+                (SOUTH_EAST, (1.0 - u).hypot(v)),
+                (SOUTH_WEST, u.hypot(v)),
+                (NORTH_WEST, u.hypot(1.0 - v)),
+            ];
+            let distance = distances.iter()
+                .filter(|(bit, _)| mask & bit != 0)
+                .map(|(_, distance)| *distance)
+                .fold(f32::MAX, f32::min);
+            let alpha = (1.0 - distance / FRINGE_WIDTH).clamp(0.0, 1.0);
+            data.extend_from_slice(&[255, 255, 255, (alpha * alpha * 255.0) as u8]);
+        }
+    }
+    Image::new(
+        Extent3d { width: size, height: size, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map_gen::height::Height;
+    use crate::materials::MaterialDefinition;
+    use crate::texture_loader::TileType;
+    use crate::tiles::Game3DSize;
+
+    fn filled_world(fill: TileType) -> World3D {
+        World3D::new(Game3DSize { x: 3, y: 3, z: 1 }, Height { value: 0 }, Some(fill.into()))
+    }
+
+    fn position(x: usize, y: usize) -> GameTilePos {
+        GameTilePos { x, y, z: 0 }
+    }
+
+    fn registry() -> MaterialRegistry {
+        let blending = [(TileType::Sand, 1), (TileType::Grass, 3), (TileType::Snow, 4)];
+        let definitions: Vec<MaterialDefinition> = blending.iter().map(|(tile_type, priority)| MaterialDefinition {
+            blend_priority: Some(*priority),
+            ..MaterialDefinition::builtin(*tile_type)
+        }).collect();
+        MaterialRegistry::new(&definitions)
+    }
+
+    #[test]
+    fn there_are_47_masks_counting_the_empty_one() {
+        let masks = canonical_masks();
+        assert_eq!(masks.len() + 1, 47);
+        assert!(!masks.contains(&0));
+        assert!(masks.iter().all(|mask| canonical(*mask) == *mask));
+    }
+
+    #[test]
+    fn corners_next_to_a_side_are_dropped() {
+        assert_eq!(canonical(NORTH | NORTH_EAST | NORTH_WEST), NORTH);
+        assert_eq!(canonical(EAST | NORTH_EAST | SOUTH_EAST | SOUTH_WEST), EAST | SOUTH_WEST);
+        assert_eq!(canonical(NORTH_EAST | SOUTH_EAST | SOUTH_WEST | NORTH_WEST), NORTH_EAST | SOUTH_EAST | SOUTH_WEST | NORTH_WEST);
+        assert_eq!(canonical(u8::MAX), NORTH | EAST | SOUTH | WEST);
+        for mask in 0..=u8::MAX {
+            assert_eq!(canonical(canonical(mask)), canonical(mask));
+        }
+    }
+
+    #[test]
+    fn neighbour_mask_only_counts_matching_neighbours() {
+        let mut world = filled_world(TileType::Stone);
+        world.set(position(1, 2), None);
+        world.set(position(2, 2), None);
+        world.set(position(0, 0), None);
+        let is_air = |voxel: Voxel| voxel.is_none();
+        // The north east corner is already covered by the north side.
+        assert_eq!(neighbour_mask(&world, position(1, 1), is_air), NORTH | SOUTH_WEST);
+        // Past the edge of the world does not count as open air.
+        assert_eq!(neighbour_mask(&world, position(2, 0), is_air), 0);
+        assert_eq!(neighbour_mask(&world, position(1, 0), is_air), WEST);
+    }
+
+    #[test]
+    fn blend_overlay_picks_the_highest_priority_above_the_tile() {
+        let registry = registry();
+        let mut world = filled_world(TileType::Sand);
+        world.set(position(2, 1), Some(TileType::Grass.into()));
+        world.set(position(1, 2), Some(TileType::Snow.into()));
+        world.set(position(0, 2), Some(TileType::Snow.into()));
+        let sand = MaterialId::from(TileType::Sand);
+        assert_eq!(blend_overlay(&world, &registry, position(1, 1), sand), Some((TileType::Snow.into(), NORTH)));
+        // Nothing outranks snow, and grass only has sand around it apart from the snow.
+        assert_eq!(blend_overlay(&world, &registry, position(1, 2), TileType::Snow.into()), None);
+        assert_eq!(blend_overlay(&world, &registry, position(2, 1), TileType::Grass.into()), Some((TileType::Snow.into(), NORTH_WEST)));
+        // Materials without a priority are outranked by any that has one.
+        let mut stone = filled_world(TileType::Stone);
+        stone.set(position(0, 1), Some(TileType::Sand.into()));
+        assert_eq!(blend_overlay(&stone, &registry, position(1, 1), TileType::Stone.into()), Some((sand, WEST)));
+        assert_eq!(blend_overlay(&stone, &registry, position(0, 1), sand), None);
+    }
+
+    // Tiles have to keep their variant every time they are drawn, in every run.
+    #[test]
+    fn position_seed_is_stable() {
+        assert_eq!(position_seed(GameTilePos { x: 1, y: 2, z: 3 }), 965692600);
+        assert_eq!(position_seed(GameTilePos { x: 100, y: 200, z: 30 }), 2289339740);
+        let seeds: Vec<u32> = (0..3).flat_map(|x| (0..3).map(move |y| position_seed(position(x, y)))).collect();
+        for (index, seed) in seeds.iter().enumerate() {
+            assert!(!seeds[index + 1..].contains(seed));
+        }
+    }
+}
@@ -1,12 +1,12 @@
-use bevy::{input::Input, math::Vec3, prelude::*, render::camera::Camera};
-use bevy_ecs_tilemap::prelude::*;
-use bevy_ecs_tilemap::tiles::{TileVisible, TileStorage, TileColor};
-use crate::map_gen::{Terrain, MapSettings, Tilemap3D};
+use bevy::{input::{Input, mouse::{MouseMotion, MouseScrollUnit, MouseWheel}}, math::Vec3, prelude::*, render::camera::Camera};
+use bevy_egui::{egui, EguiContext};
+use strum::IntoEnumIterator;
+use crate::actions::{Action, ActionInput};
+use crate::chunk_render::{map_origin, DepthShading, Falloff, ViewDepth, MAX_VIEW_DEPTH};
+use crate::map_gen::MapSettings;
 use crate::GameState::Game;
 use crate::map_gen::height::Height;
-use crate::texture_loader::TileTextureAtlas;
-use crate::tiles::{Index2D, GameTilePos};
-use crate::texture_loader::TileType::Shadow;
+use crate::save::LoadedGame;
 
 pub struct CameraPlugin;
 
@@ -19,216 +19,256 @@ impl Plugin for CameraPlugin {
         .add_system_set(
             SystemSet::on_update(Game)
                 .with_system(movement)
-                .with_system(display_layer)
+                .with_system(mouse_pan)
+                .with_system(mouse_zoom)
+                .with_system(edge_scroll)
+                .with_system(clamp_camera.after(movement).after(mouse_pan).after(mouse_zoom).after(edge_scroll))
                 .with_system(display_height_input)
-                .with_system(create_shadows)
-                //.with_system(add_shadows)
-        );
+                .with_system(view_window)
+        )
+        .init_resource::<CameraSettings>();
     }
 }
-#[derive(Resource, Clone, Copy)]
-pub struct DisplayHeight {
-    pub height: Height,
-}
-#[derive(Component)]
-pub struct Visible;
 
-pub fn display_layer(
-    mut commands: Commands,
-    display_height: Res<DisplayHeight>,
-    mut previous_height: Local<Option<Height>>,
-    map_settings: Res<MapSettings>,
-    tilemap3d: Res<Tilemap3D>,
-    tilemaps: Query<&TileStorage>,
-    mut tiles: Query<&mut TileVisible, With<Terrain>>)
-    {
-    if !display_height.is_changed() {
-        return
-    }
-    if let Some(ref mut prev_height) = *previous_height {
-        if display_height.height > *prev_height {
-            let layers = [tilemap3d.layers[display_height.height.value], tilemap3d.layers[display_height.height.value - 1]];
-            let [current_layer, previous_layer] = tilemaps.many(layers);
-
-            for index in map_settings.layer_size {
-                if let Some(entity) = current_layer.get_2d(index){
-                    if let Ok(ref mut tile) = tiles.get_mut(entity){
-                        tile.0 = true;
-                        commands.entity(entity).insert(Visible);
-                    }
-                }
-                if let Some(entity) = previous_layer.get_2d(index){
-                    if let Ok(ref mut tile) = tiles.get_mut(entity){
-                        if map_settings.heightmap[index].value != display_height.height.value - 1{
-                            tile.0 = false;
-                            commands.entity(entity).remove::<Visible>();
-                        }
-                    }
-                }
-            }
-        }
-        else if display_height.height < *prev_height {
-            let layers = [tilemap3d.layers[display_height.height.value], tilemap3d.layers[display_height.height.value + 1]];
-            let [current_layer, previous_layer] = tilemaps.many(layers);
-
-            for index in map_settings.layer_size {
-                if let Some(entity) = current_layer.get_2d(index){
-                    if let Ok(ref mut tile) = tiles.get_mut(entity){
-                        tile.0 = true;
-                        commands.entity(entity).insert(Visible);
-                    }
-                }
-                if let Some(entity) = previous_layer.get_2d(index){
-                    if let Ok(ref mut tile) = tiles.get_mut(entity){
-                        tile.0 = false;
-                        commands.entity(entity).remove::<Visible>();
-                    }
-                }
-            }
-        }
-        prev_height.value = display_height.height.value;
-    }
-    else {
-        *previous_height = Some(display_height.height);
-        let layer = tilemaps.get(tilemap3d.layers[display_height.height.value]).unwrap();
+// Zooming in further than this makes single tiles fill the screen.
+const MIN_SCALE: f32 = 0.25;
+// How much further than the whole map the camera may zoom out.
+const ZOOM_OUT_MARGIN: f32 = 1.25;
+// Large maps stop zooming out once this many tiles fit across the window, since every one of them is drawn.
+const MAX_TILES_ACROSS: f32 = 160.0;
+const ZOOM_STEP: f32 = 1.1;
+const PAN_SPEED: f32 = 250.0;
 
-        for index in map_settings.layer_size {
-            if let Some(entity) = layer.get_2d(index){
-                if let Ok(ref mut tile) = tiles.get_mut(entity){
-                    tile.0 = true;
-                    commands.entity(entity).insert(Visible);
-                }
-            }
+#[derive(Resource)]
+pub struct CameraSettings {
+    pub edge_scroll: bool,
+    // Distance from the window border, in pixels, at which edge scrolling starts.
+    pub edge_margin: f32,
+}
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            edge_scroll: false,
+            edge_margin: 16.0,
         }
     }
 }
+#[derive(Resource, Clone, Copy)]
+pub struct DisplayHeight {
+    pub height: Height,
+}
 
-
-pub fn initalize_resources(mut commands: Commands, map_settings: Res<MapSettings>){
-    commands.spawn(Camera2dBundle::default());
-    commands.insert_resource(DisplayHeight {height: Height{ value: map_settings.height_limits.min.into()}});
+pub fn initalize_resources(mut commands: Commands, map_settings: Res<MapSettings>, loaded: Option<Res<LoadedGame>>){
+    let mut camera = Camera2dBundle::default();
+    let mut display_height = DisplayHeight {height: Height{ value: map_settings.height_limits.min.into()}};
+    if let Some(loaded) = loaded {
+        camera.transform.translation = Vec3::from_array(loaded.view.camera_translation);
+        camera.projection.scale = loaded.view.camera_scale;
+        display_height.height = loaded.view.display_height;
+    }
+    commands.spawn(camera);
+    commands.insert_resource(display_height);
 }
 
 pub fn movement(
     time: Res<Time>,
-    keyboard_input: Res<Input<KeyCode>>,
+    mut actions: ActionInput,
     mut query: Query<(&mut Transform, &mut OrthographicProjection), With<Camera>>,)
     {
     for (mut transform, mut ortho) in query.iter_mut() {
         let mut direction = Vec3::ZERO;
 
-        if keyboard_input.pressed(KeyCode::A) {
+        if actions.pressed(Action::PanLeft) {
             direction -= Vec3::new(1.0, 0.0, 0.0);
         }
 
-        if keyboard_input.pressed(KeyCode::D) {
+        if actions.pressed(Action::PanRight) {
             direction += Vec3::new(1.0, 0.0, 0.0);
         }
 
-        if keyboard_input.pressed(KeyCode::W) {
+        if actions.pressed(Action::PanUp) {
             direction += Vec3::new(0.0, 1.0, 0.0);
         }
 
-        if keyboard_input.pressed(KeyCode::S) {
+        if actions.pressed(Action::PanDown) {
             direction -= Vec3::new(0.0, 1.0, 0.0);
         }
-        if keyboard_input.pressed(KeyCode::Q) {
-            ortho.scale += 0.1;
+        if actions.pressed(Action::ZoomOut) {
+            ortho.scale *= ZOOM_STEP;
         }
 
-        if keyboard_input.pressed(KeyCode::E) {
-            ortho.scale -= 0.1;
+        if actions.pressed(Action::ZoomIn) {
+            ortho.scale /= ZOOM_STEP;
         }
 
         let z = transform.translation.z;
-        transform.translation += direction * 250. * time.delta_seconds() * ortho.scale;
+        transform.translation += direction * PAN_SPEED * time.delta_seconds() * ortho.scale;
         // Important! We need to restore the Z values when moving the camera around.
         // Bevy has a specific camera setup and this can mess with how our layers are shown.
         transform.translation.z = z;
     }
 }
 
-pub fn display_height_input(keyboard_input: Res<Input<KeyCode>>, mut display_height: ResMut<DisplayHeight>, map_settings: Res<MapSettings>) {
-    /* if gametick_event.is_empty() {
-        return
-    } */
-    if keyboard_input.pressed(KeyCode::Z) {
-        display_height.height.value = (display_height.height.value + 1).clamp(map_settings.height_limits.min.into(), map_settings.height_limits.max.value - 1);
+pub fn mouse_pan(
+    mut egui_context: ResMut<EguiContext>,
+    mouse_input: Res<Input<MouseButton>>,
+    mut dragging: Local<bool>,
+    mut motion: EventReader<MouseMotion>,
+    mut query: Query<(&mut Transform, &OrthographicProjection), With<Camera>>)
+    {
+    let delta: Vec2 = motion.iter().map(|event| event.delta).sum();
+    // A drag that starts over the UI belongs to the UI until the button is let go.
+    if mouse_input.just_pressed(MouseButton::Middle) {
+        *dragging = !egui_context.ctx_mut().is_pointer_over_area();
     }
-    else if keyboard_input.pressed(KeyCode::X) {
-        if display_height.height.value != 0 {
-            display_height.height.value = (display_height.height.value - 1).clamp(map_settings.height_limits.min.into(), map_settings.height_limits.max.value - 1);
-        }
+    if !mouse_input.pressed(MouseButton::Middle) {
+        *dragging = false;
+    }
+    if !*dragging || delta == Vec2::ZERO || egui_context.ctx_mut().wants_pointer_input() {
+        return;
+    }
+    for (mut transform, ortho) in query.iter_mut() {
+        // Screen y grows downwards while world y grows upwards.
+        transform.translation.x -= delta.x * ortho.scale;
+        transform.translation.y += delta.y * ortho.scale;
     }
 }
 
-pub fn add_shadows(
-    display_height: Res<DisplayHeight>,
-    mut tiles: Query<(&Height, &mut TileColor), With<Visible>>)
+// Zooms around the cursor, so whatever is under it stays under it.
+pub fn mouse_zoom(
+    mut egui_context: ResMut<EguiContext>,
+    windows: Res<Windows>,
+    mut wheel: EventReader<MouseWheel>,
+    mut query: Query<(&mut Transform, &mut OrthographicProjection), With<Camera>>)
     {
-    if !display_height.is_changed() {
-        return
+    let scroll: f32 = wheel.iter().map(|event| match event.unit {
+        MouseScrollUnit::Line => event.y,
+        MouseScrollUnit::Pixel => event.y / 100.0,
+    }).sum();
+    if scroll == 0.0 || egui_context.ctx_mut().wants_pointer_input() {
+        return;
     }
-    for (height, mut color) in tiles.iter_mut(){
-        let height_difference = (255 - (display_height.height - *height).value) as u8;
-
-        //color.0 = Color::BLACK;
-        //color.0 = Color::rgb_u8(height_difference, height_difference, height_difference);
-        //color = TileColor(Color::rgba_u8(255, height.value as u8, 255, 255));
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let window_center = Vec2::new(window.width(), window.height()) / 2.0;
+    let cursor_offset = window.cursor_position().map_or(Vec2::ZERO, |cursor| cursor - window_center);
+    for (mut transform, mut ortho) in query.iter_mut() {
+        let anchor = transform.translation.truncate() + cursor_offset * ortho.scale;
+        ortho.scale *= ZOOM_STEP.powf(-scroll);
+        let center = anchor - cursor_offset * ortho.scale;
+        transform.translation.x = center.x;
+        transform.translation.y = center.y;
     }
 }
 
-#[derive(Component)]
-pub struct ShadowMap;
+pub fn edge_scroll(
+    time: Res<Time>,
+    settings: Res<CameraSettings>,
+    windows: Res<Windows>,
+    mut query: Query<(&mut Transform, &OrthographicProjection), With<Camera>>)
+    {
+    if !settings.edge_scroll {
+        return;
+    }
+    let (window, cursor) = match windows.get_primary().and_then(|window| window.cursor_position().map(|cursor| (window, cursor))) {
+        Some(found) => found,
+        None => return,
+    };
+    let mut direction = Vec2::ZERO;
+    if cursor.x < settings.edge_margin {
+        direction.x -= 1.0;
+    } else if cursor.x > window.width() - settings.edge_margin {
+        direction.x += 1.0;
+    }
+    // Cursor positions start at the bottom left corner of the window.
+    if cursor.y < settings.edge_margin {
+        direction.y -= 1.0;
+    } else if cursor.y > window.height() - settings.edge_margin {
+        direction.y += 1.0;
+    }
+    for (mut transform, ortho) in query.iter_mut() {
+        transform.translation += (direction * PAN_SPEED * time.delta_seconds() * ortho.scale).extend(0.0);
+    }
+}
 
-pub fn create_shadows(
-    mut commands: Commands,
+// Keeps the zoom within limits and the middle of the screen over the map.
+pub fn clamp_camera(
     map_settings: Res<MapSettings>,
-    texture_atlas: Res<TileTextureAtlas>,
-    display_height: Res<DisplayHeight>,
-    previous_shadow_map: Query<Entity, With<ShadowMap>>,
-    tiles: Query<&GameTilePos, (With<Visible>, Without<ShadowMap>)>)
+    windows: Res<Windows>,
+    mut query: Query<(&mut Transform, &mut OrthographicProjection), With<Camera>>)
     {
-    if !display_height.is_changed() {
-        return
+    let tile_size = Vec2::new(map_settings.tile_size.x, map_settings.tile_size.y);
+    let layer_size = map_settings.layer_size.size;
+    let map_size = Vec2::new(layer_size.x as f32, layer_size.y as f32) * tile_size;
+    // Tile centres start at the map origin, so the map reaches half a tile further out on every side.
+    let min = map_origin(&map_settings).truncate() - tile_size / 2.0;
+    let max = min + map_size;
+    let max_scale = match windows.get_primary() {
+        Some(window) => {
+            let whole_map = (map_size.x / window.width()).max(map_size.y / window.height()) * ZOOM_OUT_MARGIN;
+            let tile_limit = MAX_TILES_ACROSS * tile_size.x / window.width().max(window.height());
+            whole_map.min(tile_limit)
+        }
+        None => 1.0,
+    }.max(MIN_SCALE);
+
+    for (mut transform, mut ortho) in query.iter_mut() {
+        let scale = ortho.scale.clamp(MIN_SCALE, max_scale);
+        if scale != ortho.scale {
+            ortho.scale = scale;
+        }
+        let center = transform.translation.truncate().clamp(min, max);
+        if center != transform.translation.truncate() {
+            transform.translation.x = center.x;
+            transform.translation.y = center.y;
+        }
     }
+}
+
+pub fn view_window(mut egui_context: ResMut<EguiContext>, mut settings: ResMut<CameraSettings>, mut view_depth: ResMut<ViewDepth>, mut shading: ResMut<DepthShading>) {
+    egui::Window::new("View").resizable(false).show(egui_context.ctx_mut(), |ui| {
+        ui.checkbox(&mut settings.edge_scroll, "Scroll at screen edges");
+        let mut layers = view_depth.layers;
+        ui.add(egui::Slider::new(&mut layers, 0..=MAX_VIEW_DEPTH).text("Layers shown below"));
+        // Only write back real changes, every write would otherwise retint the whole view.
+        if layers != view_depth.layers {
+            view_depth.layers = layers;
+        }
+
+        ui.separator();
+        let mut edited = *shading;
+        egui::ComboBox::from_label("Fog falloff")
+            .selected_text(edited.falloff.to_string())
+            .show_ui(ui, |ui| {
+                for option in Falloff::iter() {
+                    ui.selectable_value(&mut edited.falloff, option, option.to_string());
+                }
+            });
+        ui.horizontal(|ui| {
+            let [red, green, blue, _] = edited.color.as_rgba_f32();
+            let mut color = [red, green, blue];
+            ui.color_edit_button_rgb(&mut color);
+            ui.label("Fog colour");
+            edited.color = Color::rgb(color[0], color[1], color[2]);
+        });
+        ui.add(egui::Slider::new(&mut edited.density, 0.0..=1.0).text("Fog density"));
+        ui.add(egui::Slider::new(&mut edited.occlusion, 0.0..=1.0).text("Ambient occlusion"));
+        if edited != *shading {
+            *shading = edited;
+        }
+    });
+}
 
-    for previous_entity in previous_shadow_map.iter(){
-        commands.entity(previous_entity).despawn();
-    }
-
-    let tilemap_entity = commands.spawn_empty().id();
-
-    let mut tile_storage = TileStorage::empty(map_settings.layer_size.into());
-
-    let grid_size = map_settings.tile_size.into();
-    let map_type = TilemapType::default();
-
-    for position in tiles.iter(){
-        let height_difference = (display_height.height.value.saturating_sub(position.z) as u8).saturating_mul(4);
-        let tile_2d_pos = (*position).into();
-        let tile_bundle =
-            TileBundle {
-                texture_index: texture_atlas.indices[Shadow],
-                position: tile_2d_pos,
-                tilemap_id: TilemapId(tilemap_entity),
-                color: TileColor(Color::rgba_u8(0, 0, 0, height_difference)),
-                visible: TileVisible(true),
-                ..Default::default()
-            };
-        let tile_entity = commands.spawn((tile_bundle, ShadowMap)).id();
-        tile_storage.set(&tile_2d_pos, tile_entity);
-    }
-
-    commands.entity(tilemap_entity).insert((
-        TilemapBundle {
-            grid_size,
-            map_type,
-            size: map_settings.layer_size.into(),
-            storage: tile_storage,
-            texture: TilemapTexture::Single(map_settings.texture_handle.clone()),
-            tile_size: map_settings.tile_size,
-            transform: get_tilemap_center_transform(&map_settings.layer_size.into(), &grid_size, &map_type, 1.0),
-            ..Default::default()
-        }, ShadowMap));
-}
\ No newline at end of file
+pub fn display_height_input(mut actions: ActionInput, mut display_height: ResMut<DisplayHeight>, map_settings: Res<MapSettings>) {
+    /* if gametick_event.is_empty() {
+        return
+    } */
+    if actions.pressed(Action::LayerUp) {
+        display_height.height.value = (display_height.height.value + 1).clamp(map_settings.height_limits.min.into(), map_settings.height_limits.max.value - 1);
+    }
+    else if actions.pressed(Action::LayerDown) && display_height.height.value != 0 {
+        display_height.height.value = (display_height.height.value - 1).clamp(map_settings.height_limits.min.into(), map_settings.height_limits.max.value - 1);
+    }
+}
@@ -0,0 +1,506 @@
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_ecs_tilemap::prelude::*;
+use strum::{Display, EnumIter};
+
+use crate::camera::DisplayHeight;
+use crate::map_gen::{MapSettings, Terrain, Voxel};
+use crate::autotile::{blend_overlay, neighbour_mask, position_seed, seed_flip, NORTH, EAST, SOUTH, WEST, NORTH_EAST, SOUTH_EAST, SOUTH_WEST, NORTH_WEST};
+use crate::materials::{MaterialId, MaterialRegistry};
+use crate::texture_loader::TileTextureAtlas;
+use crate::tiles::GameTilePos;
+use crate::world3d::{World3D, CHUNK_SIZE};
+use crate::GameState::Game;
+
+pub const RENDER_CHUNK_SIZE: u32 = 32;
+pub const MAX_VIEW_DEPTH: usize = 32;
+// Render chunks drawn or redrawn per frame at most, the rest wait for the following frames.
+const CHUNKS_PER_FRAME: usize = 12;
+// Deeper layers fade towards this colour by default, the deepest visible layer gets FOG_DENSITY of the way there.
+const FOG_COLOR: Color = Color::rgb(0.08, 0.09, 0.14);
+const FOG_DENSITY: f32 = 0.75;
+const OCCLUSION_STRENGTH: f32 = 0.45;
+// Overlays sit just in front of the tiles they cover, well behind the next layer up.
+const BLEND_OFFSET: f32 = 0.1;
+const EDGE_OFFSET: f32 = 0.2;
+const BLEND_ALPHA: f32 = 0.85;
+// Solid tiles darken towards open space next to them, so drops read as edges.
+const EDGE_COLOR: Color = Color::rgba(0.0, 0.0, 0.0, 0.5);
+
+// How many layers below the displayed one stay drawn, each a little more fogged than the one above it.
+#[derive(Resource, Clone, Copy)]
+pub struct ViewDepth {
+    pub layers: usize,
+}
+impl Default for ViewDepth {
+    fn default() -> Self {
+        Self { layers: 8 }
+    }
+}
+
+// How fog builds up over the visible layers, from the displayed layer at 0 to the deepest one at 1.
+#[derive(Clone, Copy, Debug, PartialEq, EnumIter, Display)]
+pub enum Falloff {
+    Linear,
+    // Little fog near the top, most of it in the last few layers.
+    Quadratic,
+    // Most of the fog right below the displayed layer.
+    SquareRoot,
+    Exponential,
+}
+impl Falloff {
+    pub fn apply(self: &Self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Falloff::Linear => t,
+            Falloff::Quadratic => t * t,
+            Falloff::SquareRoot => t.sqrt(),
+            Falloff::Exponential => (1.0 - (-3.0 * t).exp()) / (1.0 - (-3.0f32).exp()),
+        }
+    }
+}
+
+// How lower layers and enclosed floors are shaded. Changing it recolours the drawn tiles in place.
+#[derive(Resource, Clone, Copy, PartialEq)]
+pub struct DepthShading {
+    pub falloff: Falloff,
+    pub color: Color,
+    pub density: f32,
+    // How dark a floor walled in on every side gets.
+    pub occlusion: f32,
+}
+impl Default for DepthShading {
+    fn default() -> Self {
+        Self {
+            falloff: Falloff::Linear,
+            color: FOG_COLOR,
+            density: FOG_DENSITY,
+            occlusion: OCCLUSION_STRENGTH,
+        }
+    }
+}
+
+// One square of one layer of the world, drawn as its own small tilemap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RenderChunkKey {
+    pub x: u32,
+    pub y: u32,
+    pub height: usize,
+}
+
+#[derive(Component)]
+pub struct LayerChunk {
+    pub key: RenderChunkKey,
+}
+
+// What a tile entity was drawn from, so its texture and colour can be worked out again without asking World3D.
+#[derive(Component, Clone, Copy)]
+pub struct TileMaterial {
+    pub material: MaterialId,
+    // Picks the texture variant and flip.
+    pub seed: u32,
+    // How walled in the tile is by the layer above it, from 0 to 1.
+    pub occlusion: f32,
+}
+impl TileMaterial {
+    fn shaded(self: &Self, registry: &MaterialRegistry, depth_color: Color, shading: &DepthShading) -> Color {
+        let light = 1.0 - shading.occlusion * self.occlusion;
+        registry.get(self.material).apply_tint(depth_color) * Vec3::splat(light)
+    }
+}
+
+// An autotile overlay drawn over a tile, fading in from the neighbours in its mask.
+#[derive(Component, Clone, Copy)]
+pub struct TileOverlay {
+    pub mask: u8,
+    pub color: Color,
+}
+impl TileOverlay {
+    fn shaded(self: &Self, depth_color: Color) -> Color {
+        self.color * Vec3::new(depth_color.r(), depth_color.g(), depth_color.b())
+    }
+}
+
+// The tilemaps drawing a render chunk: the tiles first, then any overlays. Chunks with nothing to draw have none,
+// so they are not rebuilt every frame.
+pub struct RenderedChunk {
+    pub tilemaps: Vec<Entity>,
+    // The displayed layer when the chunk was drawn. Tiles hidden under solid tiles up to it were left out, so
+    // the chunk is redrawn once the displayed layer drops below it.
+    pub top: usize,
+    // Set when World3D changed under the chunk or the materials were reloaded, the old tilemaps stay up until
+    // the redrawn ones replace them.
+    pub stale: bool,
+}
+impl RenderedChunk {
+    fn needs_redraw(self: &Self, top: usize) -> bool {
+        self.stale || self.top > top
+    }
+}
+
+// Every render chunk that is currently streamed in.
+#[derive(Resource, Default)]
+pub struct RenderedChunks {
+    pub chunks: HashMap<RenderChunkKey, RenderedChunk>,
+}
+
+// Everything drawing a render chunk reads.
+#[derive(SystemParam)]
+pub struct ChunkSources<'w, 's> {
+    display_height: Res<'w, DisplayHeight>,
+    view_depth: Res<'w, ViewDepth>,
+    shading: Res<'w, DepthShading>,
+    map_settings: Res<'w, MapSettings>,
+    world: Res<'w, World3D>,
+    registry: Res<'w, MaterialRegistry>,
+    texture_atlas: Res<'w, TileTextureAtlas>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+pub struct ChunkRenderPlugin;
+
+impl Plugin for ChunkRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RenderedChunks>()
+        .init_resource::<ViewDepth>()
+        .init_resource::<DepthShading>()
+        .add_system_set(
+            SystemSet::on_update(Game)
+                .with_system(rebuild_changed_chunks.before(stream_chunks))
+                .with_system(redraw_reloaded_materials.before(stream_chunks))
+                .with_system(stream_chunks)
+                .with_system(tint_layers.after(stream_chunks))
+                .with_system(refresh_tile_textures.after(stream_chunks))
+        );
+    }
+}
+
+// World position of the centre of tile (0, 0), with the whole map centred on the origin.
+pub fn map_origin(map_settings: &MapSettings) -> Vec3 {
+    let grid_size = map_settings.tile_size.into();
+    get_tilemap_center_transform(&map_settings.layer_size.into(), &grid_size, &TilemapType::default(), 0.0).translation
+}
+
+// How walled in an open floor is by solid tiles on the layer above it, from 0 out in the open to 1 at the bottom
+// of a shaft. Sides count fully and corners half, since they shade less of the floor.
+fn occlusion(world: &World3D, registry: &MaterialRegistry, position: GameTilePos) -> f32 {
+    let above = GameTilePos { z: position.z + 1, ..position };
+    if is_opaque(registry, world.get(above)) {
+        return 0.0;
+    }
+    let walls = neighbour_mask(world, above, |voxel| is_opaque(registry, voxel));
+    let sides = (walls & (NORTH | EAST | SOUTH | WEST)).count_ones() as f32;
+    let corners = (walls & (NORTH_EAST | SOUTH_EAST | SOUTH_WEST | NORTH_WEST)).count_ones() as f32;
+    ((sides + corners * 0.5) / 4.0).min(1.0)
+}
+
+fn is_opaque(registry: &MaterialRegistry, voxel: Voxel) -> bool {
+    voxel.is_some_and(|material| !registry.get(material).transparent)
+}
+
+// Whether a solid tile between a tile and the displayed layer hides it, in which case it is not drawn at all.
+fn is_covered(world: &World3D, registry: &MaterialRegistry, position: GameTilePos, top: usize) -> bool {
+    (position.z + 1..=top).any(|z| is_opaque(registry, world.get(GameTilePos { z, ..position })))
+}
+
+// The lowest layer drawn below the displayed one, never below the bottom of the map.
+pub fn lowest_visible_layer(display_height: &DisplayHeight, view_depth: &ViewDepth, map_settings: &MapSettings) -> usize {
+    display_height.height.value.saturating_sub(view_depth.layers).max(map_settings.height_limits.min.value)
+}
+
+pub fn depth_tint(depth: usize, view_depth: &ViewDepth, shading: &DepthShading) -> Color {
+    let fog = shading.falloff.apply(depth as f32 / view_depth.layers.max(1) as f32) * shading.density;
+    let [fog_r, fog_g, fog_b, _] = shading.color.as_rgba_f32();
+    Color::rgb(1.0 + (fog_r - 1.0) * fog, 1.0 + (fog_g - 1.0) * fog, 1.0 + (fog_b - 1.0) * fog)
+}
+
+// The tile under a world position, which may lie off the map.
+fn world_to_tile(world_position: Vec2, map_settings: &MapSettings) -> Vec2 {
+    let tile_size = Vec2::new(map_settings.tile_size.x, map_settings.tile_size.y);
+    ((world_position - map_origin(map_settings).truncate()) / tile_size + 0.5).floor()
+}
+
+fn visible_keys(map_settings: &MapSettings, display_height: &DisplayHeight, view_depth: &ViewDepth, camera: &Transform, projection: &OrthographicProjection) -> HashSet<RenderChunkKey> {
+    let mut keys = HashSet::new();
+    let half_view = Vec2::new(projection.right - projection.left, projection.top - projection.bottom) * projection.scale / 2.0;
+    let center = camera.translation.truncate();
+
+    let min_tile = world_to_tile(center - half_view, map_settings);
+    let max_tile = world_to_tile(center + half_view, map_settings);
+    let layer_size = map_settings.layer_size.size;
+    if max_tile.x < 0.0 || max_tile.y < 0.0 || min_tile.x >= layer_size.x as f32 || min_tile.y >= layer_size.y as f32 {
+        return keys;
+    }
+    let to_chunk = |tile: f32, tiles: u32| (tile.clamp(0.0, (tiles - 1) as f32) as u32) / RENDER_CHUNK_SIZE;
+    let (min_x, max_x) = (to_chunk(min_tile.x, layer_size.x), to_chunk(max_tile.x, layer_size.x));
+    let (min_y, max_y) = (to_chunk(min_tile.y, layer_size.y), to_chunk(max_tile.y, layer_size.y));
+
+    let top = display_height.height.value;
+    let bottom = lowest_visible_layer(display_height, view_depth, map_settings);
+    for x in min_x..=max_x {
+        for y in min_y..=max_y {
+            for height in bottom..=top {
+                keys.insert(RenderChunkKey { x, y, height });
+            }
+        }
+    }
+    keys
+}
+
+fn spawn_layer_chunk(commands: &mut Commands, key: RenderChunkKey, sources: &ChunkSources) -> Vec<Entity> {
+    let ChunkSources { display_height, view_depth, shading, map_settings, world, registry, texture_atlas, .. } = sources;
+    let layer_size = map_settings.layer_size.size;
+    let (start_x, start_y) = (key.x * RENDER_CHUNK_SIZE, key.y * RENDER_CHUNK_SIZE);
+    let size = TilemapSize {
+        x: RENDER_CHUNK_SIZE.min(layer_size.x - start_x),
+        y: RENDER_CHUNK_SIZE.min(layer_size.y - start_y),
+    };
+
+    let top = display_height.height.value;
+    let mut tiles = Vec::new();
+    let mut blends = Vec::new();
+    let mut edges = Vec::new();
+    for local_x in 0..size.x {
+        for local_y in 0..size.y {
+            let position = GameTilePos { x: (start_x + local_x) as usize, y: (start_y + local_y) as usize, z: key.height };
+            let tile_pos = TilePos { x: local_x, y: local_y };
+            let material = match world.get(position) {
+                Some(material) if !is_covered(world, registry, position, top) => material,
+                _ => continue,
+            };
+            let definition = registry.get(material);
+            let tile_material = TileMaterial { material, seed: position_seed(position), occlusion: occlusion(world, registry, position) };
+            tiles.push((tile_pos, tile_material, definition.flip));
+            if let Some((bleeding, mask)) = blend_overlay(world, registry, position, material) {
+                let mut color = registry.get(bleeding).map_color();
+                color.set_a(BLEND_ALPHA);
+                blends.push((tile_pos, TileOverlay { mask, color }));
+            }
+            if !definition.transparent {
+                let mask = neighbour_mask(world, position, |voxel| voxel.is_none());
+                if mask != 0 {
+                    edges.push((tile_pos, TileOverlay { mask, color: EDGE_COLOR }));
+                }
+            }
+        }
+    }
+    if tiles.is_empty() {
+        return Vec::new();
+    }
+
+    let depth_color = depth_tint(top - key.height, view_depth, shading);
+    let tilemap_entity = commands.spawn_empty().id();
+    let mut tile_storage = TileStorage::empty(size);
+    for (tile_pos, tile_material, flip) in tiles {
+        let tile_entity = commands.spawn((
+            TileBundle {
+                position: tile_pos,
+                texture_index: texture_atlas.texture_index(tile_material.material, tile_material.seed),
+                tilemap_id: TilemapId(tilemap_entity),
+                flip: if flip { seed_flip(tile_material.seed) } else { TileFlip::default() },
+                color: TileColor(tile_material.shaded(registry, depth_color, shading)),
+                ..Default::default()
+            },
+            tile_material,
+            Terrain
+        )).id();
+        tile_storage.set(&tile_pos, tile_entity);
+    }
+    insert_layer_tilemap(commands, tilemap_entity, key, tile_storage, 0.0, map_settings);
+
+    let mut tilemaps = vec![tilemap_entity];
+    for (overlays, z_offset) in [(blends, BLEND_OFFSET), (edges, EDGE_OFFSET)] {
+        if overlays.is_empty() {
+            continue;
+        }
+        let overlay_entity = commands.spawn_empty().id();
+        let mut overlay_storage = TileStorage::empty(size);
+        for (tile_pos, overlay) in overlays {
+            let tile_entity = commands.spawn((
+                TileBundle {
+                    position: tile_pos,
+                    texture_index: texture_atlas.mask_index(overlay.mask),
+                    tilemap_id: TilemapId(overlay_entity),
+                    color: TileColor(overlay.shaded(depth_color)),
+                    ..Default::default()
+                },
+                overlay
+            )).id();
+            overlay_storage.set(&tile_pos, tile_entity);
+        }
+        insert_layer_tilemap(commands, overlay_entity, key, overlay_storage, z_offset, map_settings);
+        tilemaps.push(overlay_entity);
+    }
+    tilemaps
+}
+
+fn insert_layer_tilemap(commands: &mut Commands, tilemap_entity: Entity, key: RenderChunkKey, storage: TileStorage, z_offset: f32, map_settings: &MapSettings) {
+    let grid_size = map_settings.tile_size.into();
+    // Higher layers sit in front of lower ones, so a solid tile hides whatever lies beneath it.
+    let offset = Vec3::new(
+        (key.x * RENDER_CHUNK_SIZE) as f32 * map_settings.tile_size.x,
+        (key.y * RENDER_CHUNK_SIZE) as f32 * map_settings.tile_size.y,
+        key.height as f32 + z_offset,
+    );
+    commands.entity(tilemap_entity).insert((
+        TilemapBundle {
+            grid_size,
+            map_type: TilemapType::default(),
+            size: storage.size,
+            storage,
+            texture: TilemapTexture::Single(map_settings.texture_handle.clone()),
+            tile_size: map_settings.tile_size,
+            transform: Transform::from_translation(map_origin(map_settings) + offset),
+            ..Default::default()
+        },
+        LayerChunk { key }
+    ));
+}
+
+fn despawn_layer_chunk(commands: &mut Commands, tilemaps: &[Entity], storages: &Query<&TileStorage>) {
+    for tilemap_entity in tilemaps {
+        if let Ok(storage) = storages.get(*tilemap_entity) {
+            for tile_entity in storage.iter().flatten() {
+                commands.entity(*tile_entity).despawn();
+            }
+        }
+        commands.entity(*tilemap_entity).despawn();
+    }
+}
+
+pub fn stream_chunks(
+    mut commands: Commands,
+    mut rendered: ResMut<RenderedChunks>,
+    sources: ChunkSources,
+    cameras: Query<(&Transform, &OrthographicProjection), With<Camera>>,
+    storages: Query<&TileStorage>)
+    {
+    let (camera, projection) = match cameras.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    let ChunkSources { display_height, view_depth, map_settings, .. } = &sources;
+    let wanted = visible_keys(map_settings, display_height, view_depth, camera, projection);
+
+    let unwanted: Vec<RenderChunkKey> = rendered.chunks.keys().filter(|key| !wanted.contains(key)).copied().collect();
+    for key in unwanted {
+        if let Some(chunk) = rendered.chunks.remove(&key) {
+            despawn_layer_chunk(&mut commands, &chunk.tilemaps, &storages);
+        }
+    }
+
+    // Missing chunks come before ones that only need redrawing, then the ones nearest the middle of the screen,
+    // top layer first.
+    let top = display_height.height.value;
+    let center = world_to_tile(camera.translation.truncate(), map_settings) / RENDER_CHUNK_SIZE as f32;
+    let mut pending: Vec<(bool, f32, RenderChunkKey)> = wanted.into_iter().filter_map(|key| {
+        let redraw = match rendered.chunks.get(&key) {
+            Some(chunk) if chunk.needs_redraw(top) => true,
+            Some(_) => return None,
+            None => false,
+        };
+        let distance = (Vec2::new(key.x as f32, key.y as f32) + 0.5).distance_squared(center);
+        Some((redraw, distance, key))
+    }).collect();
+    pending.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)).then(b.2.height.cmp(&a.2.height)));
+    for (_, _, key) in pending.into_iter().take(CHUNKS_PER_FRAME) {
+        let tilemaps = spawn_layer_chunk(&mut commands, key, &sources);
+        if let Some(old) = rendered.chunks.insert(key, RenderedChunk { tilemaps, top, stale: false }) {
+            despawn_layer_chunk(&mut commands, &old.tilemaps, &storages);
+        }
+    }
+}
+
+// Marks the render chunks overlapping anything simulation code changed in World3D, streaming redraws them.
+// Autotiling looks at neighbours and whether a tile is hidden depends on every layer above it, so render chunks
+// touching the changed chunk from the side or lying anywhere below it are redrawn as well.
+pub fn rebuild_changed_chunks(mut rendered: ResMut<RenderedChunks>, mut world: ResMut<World3D>) {
+    if !world.has_dirty_chunks() {
+        return;
+    }
+    let min_height = world.min_height().value;
+    for chunk_pos in world.take_dirty_chunks() {
+        let (tile_x, tile_y) = ((chunk_pos.x * CHUNK_SIZE) as u32, (chunk_pos.y * CHUNK_SIZE) as u32);
+        let chunks_x = (tile_x.saturating_sub(1) / RENDER_CHUNK_SIZE)..=((tile_x + CHUNK_SIZE as u32) / RENDER_CHUNK_SIZE);
+        let chunks_y = (tile_y.saturating_sub(1) / RENDER_CHUNK_SIZE)..=((tile_y + CHUNK_SIZE as u32) / RENDER_CHUNK_SIZE);
+        let top = min_height + (chunk_pos.z + 1) * CHUNK_SIZE;
+        for (key, chunk) in rendered.chunks.iter_mut() {
+            if chunks_x.contains(&key.x) && chunks_y.contains(&key.y) && key.height < top {
+                chunk.stale = true;
+            }
+        }
+    }
+}
+
+// Overlay colours and blend priorities are baked into the drawn chunks, so reloaded material definitions redraw
+// all of them. Until streaming gets to a chunk, tint_layers recolours its tiles.
+pub fn redraw_reloaded_materials(registry: Res<MaterialRegistry>, mut rendered: ResMut<RenderedChunks>) {
+    if !registry.is_changed() {
+        return;
+    }
+    for chunk in rendered.chunks.values_mut() {
+        chunk.stale = true;
+    }
+}
+
+// Streaming takes care of which layers are drawn after any jump of the displayed layer, this only recolours
+// the layers that stay.
+pub fn tint_layers(
+    display_height: Res<DisplayHeight>,
+    view_depth: Res<ViewDepth>,
+    shading: Res<DepthShading>,
+    registry: Res<MaterialRegistry>,
+    layers: Query<(&LayerChunk, &TileStorage)>,
+    mut tiles: Query<(&mut TileColor, Option<&TileMaterial>, Option<&TileOverlay>)>)
+    {
+    if !display_height.is_changed() && !view_depth.is_changed() && !shading.is_changed() && !registry.is_changed() {
+        return;
+    }
+    for (layer, storage) in layers.iter() {
+        let depth_color = depth_tint(display_height.height.value.saturating_sub(layer.key.height), &view_depth, &shading);
+        for tile_entity in storage.iter().flatten() {
+            if let Ok((mut tile_color, material, overlay)) = tiles.get_mut(*tile_entity) {
+                if let Some(material) = material {
+                    tile_color.0 = material.shaded(&registry, depth_color, &shading);
+                } else if let Some(overlay) = overlay {
+                    tile_color.0 = overlay.shaded(depth_color);
+                }
+            }
+        }
+    }
+}
+
+// After the atlas is rebuilt by a reload, points every drawn layer at the new atlas image and every tile at
+// its texture's new place in it.
+pub fn refresh_tile_textures(
+    texture_atlas: Res<TileTextureAtlas>,
+    mut map_settings: ResMut<MapSettings>,
+    mut tilemaps: Query<&mut TilemapTexture, With<LayerChunk>>,
+    mut tiles: Query<(&mut TileTextureIndex, Option<&TileMaterial>, Option<&TileOverlay>)>)
+    {
+    if !texture_atlas.is_changed() {
+        return;
+    }
+    if let Some(atlas) = texture_atlas.atlas.as_ref() {
+        if map_settings.texture_handle != atlas.texture {
+            map_settings.texture_handle = atlas.texture.clone();
+            for mut texture in tilemaps.iter_mut() {
+                *texture = TilemapTexture::Single(atlas.texture.clone());
+            }
+        }
+    }
+    for (mut texture_index, material, overlay) in tiles.iter_mut() {
+        let new_index = match (material, overlay) {
+            (Some(material), _) => texture_atlas.texture_index(material.material, material.seed),
+            (None, Some(overlay)) => texture_atlas.mask_index(overlay.mask),
+            (None, None) => continue,
+        };
+        if texture_index.0 != new_index.0 {
+            *texture_index = new_index;
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use bevy::prelude::Handle;
+use bevy_ecs_tilemap::prelude::TilemapTileSize;
+use clap::{Args, Parser, Subcommand};
+
+use crate::materials::MaterialRegistry;
+use crate::map_gen::{HeightMap, MapSettings, WorldGenSettings, WorldPreset, TILE_PIXEL_LENGTH};
+use crate::save::{LoadedGame, SaveGame, SavedClock, SavedView};
+use crate::tiles::GameTilePos;
+use crate::world3d::World3D;
+
+pub const EXIT_FAILURE: u8 = 1;
+
+#[derive(Parser)]
+#[command(name = "rusted-fortress", about = "Dwarf Fortress-esque game made in Bevy")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    // Also presets the world setup screen when starting the game normally.
+    #[command(flatten)]
+    pub world: WorldArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Generate a world, print a summary of it and optionally save it
+    Generate {
+        #[command(flatten)]
+        world: WorldArgs,
+        /// Save file to write the generated world to
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Run the simulation without a window
+    Simulate {
+        #[command(flatten)]
+        world: WorldArgs,
+        /// Continue from a save instead of generating a new world
+        #[arg(long)]
+        load: Option<PathBuf>,
+        /// Stop after this many game ticks, runs forever otherwise
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        ticks: Option<u64>,
+        /// Save file to write once the run stops
+        #[arg(long)]
+        save: Option<PathBuf>,
+    },
+    /// Print a summary of a saved world
+    Stats {
+        save: PathBuf,
+    },
+}
+
+#[derive(Args, Clone, Default)]
+pub struct WorldArgs {
+    /// World seed, random when left out
+    #[arg(long)]
+    pub seed: Option<u32>,
+    /// World preset, such as standard or highlands
+    #[arg(long)]
+    pub preset: Option<WorldPreset>,
+    /// Width and length of the map in tiles, overriding the preset
+    #[arg(long, value_parser = clap::value_parser!(u32).range(16..=512))]
+    pub size: Option<u32>,
+}
+impl WorldArgs {
+    pub fn world_gen_settings(self: &Self) -> WorldGenSettings {
+        let mut settings = WorldGenSettings::default();
+        if let Some(preset) = self.preset {
+            settings.apply_preset(preset);
+        }
+        if let Some(seed) = self.seed {
+            settings.seed = seed;
+        }
+        if let Some(size) = self.size {
+            settings.layer_size = size as usize;
+            settings.preset = WorldPreset::Custom;
+        }
+        settings
+    }
+}
+
+pub fn generate(world_args: &WorldArgs, out: Option<&Path>) -> ExitCode {
+    let settings = world_args.world_gen_settings();
+    let tile_size = TilemapTileSize { x: TILE_PIXEL_LENGTH, y: TILE_PIXEL_LENGTH };
+    let (map_settings, world) = MapSettings::generate(&settings, tile_size, Handle::default());
+    let registry = MaterialRegistry::load_default();
+    print_summary(map_settings.seed, &map_settings.heightmap, &world, &registry);
+
+    if let Some(path) = out {
+        let save = SaveGame::capture(&map_settings, &world, &registry, SavedView::starting(&map_settings), SavedClock::default(), Vec::new());
+        if let Err(error) = save.write(path) {
+            eprintln!("Could not write {}: {}", path.display(), error);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+        println!("Saved to {}", path.display());
+    }
+    ExitCode::SUCCESS
+}
+
+pub fn stats(path: &Path) -> ExitCode {
+    let registry = MaterialRegistry::load_default();
+    match LoadedGame::read(path, &registry) {
+        Ok(loaded) => {
+            let world = match loaded.world.as_ref() {
+                Some(world) => world,
+                None => {
+                    eprintln!("Could not read {}: the save holds no world", path.display());
+                    return ExitCode::from(EXIT_FAILURE);
+                }
+            };
+            print_summary(loaded.map.settings.seed, &loaded.map.heightmap, world, &registry);
+            println!("Entities: {}", loaded.entities.len());
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("Could not read {}: {}", path.display(), error);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+pub fn print_summary(seed: u32, heightmap: &HeightMap, world: &World3D, registry: &MaterialRegistry) {
+    let size = world.size();
+    println!("Seed: {}", seed);
+    println!("Size: {}x{}x{}, starting at height {}", size.x, size.y, size.z, world.min_height().value);
+
+    let mut heights = BTreeMap::new();
+    for height in heightmap.iter() {
+        *heights.entry(height.value).or_insert(0usize) += 1;
+    }
+    let widest = heights.values().copied().max().unwrap_or(1);
+    println!("Surface heights:");
+    for (height, count) in heights {
+        println!("{:>5} {:>8} {}", height, count, "#".repeat((count * 50).div_ceil(widest)));
+    }
+
+    let mut materials = BTreeMap::new();
+    let min = world.min_height().value;
+    for x in 0..size.x {
+        for y in 0..size.y {
+            for z in min..min + size.z {
+                let name = match world.get(GameTilePos { x, y, z }) {
+                    Some(id) => registry.name(id).to_string(),
+                    None => String::from("air"),
+                };
+                *materials.entry(name).or_insert(0usize) += 1;
+            }
+        }
+    }
+    println!("Materials:");
+    for (name, count) in materials {
+        println!("{:>12} {:>10}", name, count);
+    }
+}
@@ -1,5 +1,6 @@
 use bevy::{prelude::*, diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin}};
 use crate::camera::DisplayHeight;
+use crate::map_gen::MapSettings;
 use crate::GameState::Game;
 
 #[derive(Component)]
@@ -54,12 +55,25 @@ pub fn spawn_debug_text(mut commands: Commands, asset_server: Res<AssetServer>){
                 font_size: DEBUG_FONT_SIZE,
                 color: Color::GOLD,
             }),
+            TextSection::new(
+                " Seed: ",
+                TextStyle {
+                    font: asset_server.load(DEBUG_FONT),
+                    font_size: DEBUG_FONT_SIZE,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::from_style(TextStyle {
+                font: asset_server.load(DEBUG_FONT),
+                font_size: DEBUG_FONT_SIZE,
+                color: Color::GOLD,
+            }),
         ]),
         DebugText
     ));
 }
 
-pub fn update_debug_text(diagnostics: Res<Diagnostics>, display_height: Res<DisplayHeight>, mut query: Query<&mut Text, With<DebugText>>) {
+pub fn update_debug_text(diagnostics: Res<Diagnostics>, display_height: Res<DisplayHeight>, map_settings: Res<MapSettings>, mut query: Query<&mut Text, With<DebugText>>) {
     for mut text in &mut query {
         if let Some(fps) = diagnostics.get(FrameTimeDiagnosticsPlugin::FPS) {
             if let Some(value) = fps.smoothed() {
@@ -67,5 +81,6 @@ pub fn update_debug_text(diagnostics: Res<Diagnostics>, display_height: Res<Disp
             }
         }
         text.sections[3].value = format!("{}", display_height.height.value);
+        text.sections[5].value = format!("{}", map_settings.seed);
     }
 }
\ No newline at end of file
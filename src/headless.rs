@@ -0,0 +1,118 @@
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use bevy::app::AppExit;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+
+use crate::cli::{print_summary, EXIT_FAILURE};
+use crate::map_gen::MapSettings;
+use crate::materials::MaterialRegistry;
+use crate::save::{SaveGame, SavedClock, SavedEntity, SavedView};
+use crate::tiles::GameTilePos;
+use crate::world3d::World3D;
+use crate::GameState::Game;
+use crate::tick::{GameClock, GameTick, SimulationStage};
+
+// Runs the simulation without a window, stopping after a number of game ticks when a limit is given
+// and saving the world once it stops.
+pub struct HeadlessPlugin {
+    pub ticks: Option<u64>,
+    pub save: Option<PathBuf>,
+    pub outcome: HeadlessOutcome,
+}
+
+// How a headless run ended. Running the app consumes it along with its resources, so main keeps a clone
+// of this to read the exit code from afterwards.
+#[derive(Resource, Clone, Default)]
+pub struct HeadlessOutcome {
+    code: Arc<AtomicU8>,
+}
+impl HeadlessOutcome {
+    pub fn fail(self: &Self) {
+        self.code.store(EXIT_FAILURE, Ordering::Relaxed);
+    }
+    pub fn exit_code(self: &Self) -> ExitCode {
+        ExitCode::from(self.code.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Resource)]
+struct HeadlessRun {
+    ticks: Option<u64>,
+    save: Option<PathBuf>,
+}
+
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        // Without a window there is nobody waiting on the wall clock, so every frame runs one tick.
+        let mut clock = GameClock::default();
+        clock.realtime = false;
+        app.insert_resource(clock)
+        .insert_resource(HeadlessRun { ticks: self.ticks, save: self.save.clone() })
+        .insert_resource(self.outcome.clone())
+        .add_system_set(
+            SystemSet::on_enter(Game)
+                .with_system(report_world)
+        )
+        // Checked once per tick rather than per frame, so the run stops right on its limit.
+        .add_system_to_stage(SimulationStage, count_ticks);
+    }
+}
+
+fn report_world(map_settings: Res<MapSettings>, world: Res<World3D>, registry: Res<MaterialRegistry>) {
+    print_summary(map_settings.seed, &map_settings.heightmap, &world, &registry);
+}
+
+// What a headless run saves once it stops.
+#[derive(SystemParam)]
+struct RunSources<'w, 's> {
+    map_settings: Res<'w, MapSettings>,
+    world: Res<'w, World3D>,
+    registry: Res<'w, MaterialRegistry>,
+    clock: Res<'w, GameClock>,
+    tick: Res<'w, GameTick>,
+    entities: Query<'w, 's, (&'static GameTilePos, Option<&'static Name>)>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+impl<'w, 's> RunSources<'w, 's> {
+    fn capture(self: &Self) -> SaveGame {
+        let entities = self.entities.iter().map(|(position, name)| SavedEntity {
+            position: *position,
+            name: name.map(|name| name.as_str().to_string()),
+        }).collect();
+        SaveGame::capture(&self.map_settings, &self.world, &self.registry, SavedView::starting(&self.map_settings), SavedClock::capture(&self.clock, &self.tick), entities)
+    }
+}
+
+fn count_ticks(
+    run: Res<HeadlessRun>,
+    outcome: Res<HeadlessOutcome>,
+    mut start: Local<Option<u64>>,
+    mut exit: EventWriter<AppExit>,
+    sources: RunSources)
+    {
+    // Loaded games start from their saved tick, the limit counts ticks simulated in this run. The first run
+    // of the stage comes right after the first tick, clap rejects a limit of 0.
+    let tick = sources.tick.0;
+    let ticks = tick - *start.get_or_insert(tick - 1);
+    match run.ticks {
+        Some(limit) if ticks >= limit => {}
+        _ => return,
+    }
+    println!("Simulated {} ticks, now at tick {}", ticks, tick);
+    if let Some(path) = &run.save {
+        match sources.capture().write(path) {
+            Ok(()) => println!("Saved to {}", path.display()),
+            Err(error) => {
+                eprintln!("Could not write {}: {}", path.display(), error);
+                outcome.fail();
+            }
+        }
+    }
+    exit.send(AppExit);
+}
@@ -0,0 +1,120 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+use crate::map_gen::MapSettings;
+use crate::materials::MaterialRegistry;
+use crate::picking::HoveredTile;
+use crate::tiles::GameTilePos;
+use crate::world3d::World3D;
+use crate::GameState::Game;
+
+// A tile clicked on stays in the inspector until another tile is clicked, or the same one again to let go.
+#[derive(Resource, Default)]
+pub struct SelectedTile {
+    pub position: Option<GameTilePos>,
+}
+
+pub struct InspectorPlugin;
+
+impl Plugin for InspectorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SelectedTile>()
+        .add_system_set(
+            SystemSet::on_update(Game)
+                .with_system(select_tile)
+                .with_system(inspector_window.after(select_tile))
+        );
+    }
+}
+
+fn select_tile(mouse_input: Res<Input<MouseButton>>, hovered: Res<HoveredTile>, mut selected: ResMut<SelectedTile>) {
+    if !mouse_input.just_pressed(MouseButton::Left) {
+        return;
+    }
+    // Clicks over the UI never reach a tile, so they leave the selection alone.
+    if let Some(position) = hovered.position {
+        selected.position = if selected.position == Some(position) { None } else { Some(position) };
+    }
+}
+
+fn inspector_window(
+    mut egui_context: ResMut<EguiContext>,
+    hovered: Res<HoveredTile>,
+    selected: Res<SelectedTile>,
+    map_settings: Res<MapSettings>,
+    mut world: ResMut<World3D>,
+    registry: Res<MaterialRegistry>,
+    entities: Query<(Entity, &GameTilePos, Option<&Name>)>)
+    {
+    let position = match selected.position.or(hovered.position) {
+        Some(position) => position,
+        None => return,
+    };
+    let column = (position.x, position.y);
+    let title = if selected.position.is_some() { "Inspector (selected)" } else { "Inspector" };
+    egui::Window::new(title).id(egui::Id::new("inspector")).resizable(false).show(egui_context.ctx_mut(), |ui| {
+        egui::Grid::new("inspector_grid").num_columns(2).spacing([20.0, 4.0]).show(ui, |ui| {
+            ui.label("Position");
+            ui.label(format!("{}, {}, {}", position.x, position.y, position.z));
+            ui.end_row();
+
+            let material = world.get(position).map(|id| registry.get(id));
+            ui.label("Material");
+            ui.label(material.map_or("air", |material| material.name.as_str()));
+            ui.end_row();
+
+            if let Some(material) = material {
+                ui.label("Hardness");
+                ui.label(format!("{:.1}", material.hardness));
+                ui.end_row();
+
+                ui.label("Yields");
+                ui.label(material.yields.as_deref().unwrap_or("nothing"));
+                ui.end_row();
+
+                if material.liquid || material.transparent {
+                    let flags: Vec<&str> = [(material.liquid, "liquid"), (material.transparent, "transparent")].into_iter()
+                        .filter_map(|(set, flag)| set.then_some(flag))
+                        .collect();
+                    ui.label("Flags");
+                    ui.label(flags.join(", "));
+                    ui.end_row();
+                }
+            }
+
+            ui.label("Surface height");
+            ui.label(map_settings.heightmap[column].value.to_string());
+            ui.end_row();
+
+            ui.label("Biome");
+            ui.label(map_settings.biome(position).to_string());
+            ui.end_row();
+
+            ui.label("Temperature");
+            ui.label(format!("{:.2}", map_settings.temperature[column]));
+            ui.end_row();
+
+            ui.label("Moisture");
+            ui.label(format!("{:.2}", map_settings.moisture[column]));
+            ui.end_row();
+        });
+
+        // Only a tile picked on purpose can be dug out, never whatever the cursor happens to pass over.
+        let diggable = world.get(position).map(|id| !registry.get(id).liquid).unwrap_or(false);
+        if selected.position.is_some() && diggable && ui.button("Dig out").clicked() {
+            world.set(position, None);
+        }
+
+        ui.separator();
+        let occupants: Vec<String> = entities.iter()
+            .filter(|(_, entity_position, _)| **entity_position == position)
+            .map(|(entity, _, name)| name.map_or_else(|| format!("Entity {:?}", entity), |name| name.as_str().to_string()))
+            .collect();
+        if occupants.is_empty() {
+            ui.label("Nothing here");
+        }
+        for occupant in occupants {
+            ui.label(occupant);
+        }
+    });
+}
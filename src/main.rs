@@ -1,45 +1,51 @@
+use std::process::ExitCode;
+
 use bevy::prelude::*;
 use bevy_ecs_tilemap::prelude::*;
+use actions::ActionsPlugin;
 use camera::CameraPlugin;
+use clap::Parser;
+use cli::{Cli, Command};
+use chunk_render::ChunkRenderPlugin;
 use debug::DebugPlugin;
+use headless::{HeadlessOutcome, HeadlessPlugin};
+use inspector::InspectorPlugin;
 use main_menu::MainMenuPlugin;
 use map_gen::MapGeneratorPlugin;
+use materials::MaterialRegistry;
+use picking::PickingPlugin;
+use save::{LoadedGame, SavePlugin};
 use strum::EnumIter;
 use int_enum::IntEnum;
 use texture_loader::TextureLoaderPlugin;
+use tick::{TickControlsPlugin, TickPlugin};
 
+mod actions;
+mod autotile;
 mod camera;
+mod cli;
+mod chunk_render;
 mod map_gen;
+mod materials;
 mod debug;
+mod headless;
+mod inspector;
 mod main_menu;
+mod picking;
+mod save;
 mod texture_loader;
+mod tick;
 mod tiles;
+mod world3d;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, EnumIter, IntEnum)]
 pub enum GameState {
     InitalizeAssets = 0,
     MainMenu = 1,
-    WorldGen = 2,
-    Game = 3,
-}
-pub struct GameTickEvent; // Send a game tick every 20 engine ticks
-
-#[derive(Resource)]
-struct GameSpeed(usize);
-
-impl Default for GameSpeed{
-    fn default() -> Self {
-        Self(20)
-    }
-}
-
-fn gametick_event_counter(game_speed: Res<GameSpeed>, mut tick_counter: Local<usize>, mut gametick_event: EventWriter<GameTickEvent>){
-    *tick_counter += 1;
-    if *tick_counter >= game_speed.0 {
-        gametick_event.send(GameTickEvent);
-        *tick_counter = 0;
-    }
+    WorldSetup = 2,
+    WorldGen = 3,
+    Game = 4,
 }
 
 fn next_game_state(mut game_state: ResMut<State<GameState>>){
@@ -48,39 +54,72 @@ fn next_game_state(mut game_state: ResMut<State<GameState>>){
     game_state.set(next_state).unwrap();
 }
 
-struct MainGamePlugin;
-
-impl Plugin for MainGamePlugin {
-    fn build(&self, app: &mut App) {
-        app.add_plugins(DefaultPlugins.set(WindowPlugin{
-            window: WindowDescriptor {
-                width: 1080.0,
-                height: 720.0,
-                title: String::from(
-                    "Rusted Fortress",
-                ),
-                ..Default::default()
-            },
-            ..default()
-        }).set(ImagePlugin::default_nearest()))
-        .init_resource::<GameSpeed>()
-        .add_event::<GameTickEvent>()
-        .add_system(gametick_event_counter);
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut app = App::new();
+    let outcome = HeadlessOutcome::default();
+    match cli.command {
+        Some(Command::Generate { world, out }) => return cli::generate(&world, out.as_deref()),
+        Some(Command::Stats { save }) => return cli::stats(&save),
+        Some(Command::Simulate { world, load, ticks, save }) => {
+            // Without the asset server the material definitions are read straight from disk.
+            let registry = MaterialRegistry::load_default();
+            if let Some(path) = load {
+                match LoadedGame::read(&path, &registry) {
+                    Ok(loaded) => {
+                        app.insert_resource(loaded);
+                    }
+                    Err(error) => {
+                        eprintln!("Could not read {}: {}", path.display(), error);
+                        return ExitCode::from(cli::EXIT_FAILURE);
+                    }
+                }
+            }
+            app.add_plugins(MinimalPlugins)
+                .add_plugin(TickPlugin)
+                .insert_resource(world.world_gen_settings())
+                .insert_resource(registry)
+                .add_state(GameState::WorldGen)
+                .add_plugin(MapGeneratorPlugin)
+                .add_plugin(SavePlugin)
+                .add_plugin(HeadlessPlugin { ticks, save, outcome: outcome.clone() });
+        }
+        None => {
+            app.insert_resource(TilemapRenderSettings {
+                    render_chunk_size: UVec2::new(32, 32),
+                })
+                .insert_resource(cli.world.world_gen_settings())
+                .add_plugins(DefaultPlugins.set(WindowPlugin{
+                    window: WindowDescriptor {
+                        width: 1080.0,
+                        height: 720.0,
+                        title: String::from(
+                            "Rusted Fortress",
+                        ),
+                        ..Default::default()
+                    },
+                    ..default()
+                }).set(ImagePlugin::default_nearest()).set(AssetPlugin {
+                    // Picks up edits to tile textures and material definitions while the game runs.
+                    watch_for_changes: true,
+                    ..default()
+                }))
+                .add_plugin(TickPlugin)
+                .add_plugin(TickControlsPlugin)
+                .add_state(GameState::from_int(0).unwrap())
+                .add_plugin(TilemapPlugin)
+                .add_plugin(MapGeneratorPlugin)
+                .add_plugin(ActionsPlugin)
+                .add_plugin(CameraPlugin)
+                .add_plugin(ChunkRenderPlugin)
+                .add_plugin(PickingPlugin)
+                .add_plugin(InspectorPlugin)
+                .add_plugin(DebugPlugin)
+                .add_plugin(MainMenuPlugin)
+                .add_plugin(SavePlugin)
+                .add_plugin(TextureLoaderPlugin);
+        }
     }
-}
-
-fn main() {
-    App::new()
-        .insert_resource(TilemapRenderSettings {
-            render_chunk_size: UVec2::new(32, 32),
-        })
-        .add_plugin(MainGamePlugin)
-        .add_state(GameState::from_int(0).unwrap())
-        .add_plugin(TilemapPlugin)
-        .add_plugin(MapGeneratorPlugin)
-        .add_plugin(CameraPlugin)
-        .add_plugin(DebugPlugin)
-        .add_plugin(MainMenuPlugin)
-        .add_plugin(TextureLoaderPlugin)
-        .run();
+    app.run();
+    outcome.exit_code()
 }
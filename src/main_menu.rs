@@ -1,6 +1,14 @@
-use bevy::prelude::{Plugin, ResMut, App, SystemSet, State};
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::{Plugin, Res, ResMut, App, SystemSet, State, Commands, Local};
 use bevy_egui::{egui, EguiContext, EguiPlugin};
-use crate::GameState::{WorldGen, MainMenu, self};
+use strum::IntoEnumIterator;
+use crate::GameState::{WorldGen, WorldSetup, MainMenu, Game, self};
+use crate::map_gen::{MapHeight, WorldGenSettings, WorldPreset, random_seed};
+use crate::map_gen::heightmap_image::{import_heightmap, export_menu};
+use crate::map_gen::terrain::FractalKind;
+use crate::materials::MaterialRegistry;
+use crate::save::{LoadedGame, SAVE_DIRECTORY, list_saves, save_menu};
 
 pub struct MainMenuPlugin;
 
@@ -10,11 +18,27 @@ impl Plugin for MainMenuPlugin {
         .add_system_set(
             SystemSet::on_update(MainMenu)
                 .with_system(main_menu)
+        )
+        .add_system_set(
+            SystemSet::on_update(WorldSetup)
+                .with_system(world_setup)
+        )
+        .add_system_set(
+            SystemSet::on_update(Game)
+                .with_system(save_menu)
+                .with_system(export_menu)
         );
     }
 }
 
-fn main_menu(mut egui_context: ResMut<EguiContext>, mut state: ResMut<State<GameState>>) {
+fn main_menu(
+    mut commands: Commands,
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameState>>,
+    registry: Res<MaterialRegistry>,
+    mut saves: Local<Option<Vec<PathBuf>>>,
+    mut load_error: Local<Option<String>>)
+    {
     egui::SidePanel::left("left").default_width(400.0).show(egui_context.ctx_mut(), |_| {});
     egui::SidePanel::right("right").default_width(400.0).show(egui_context.ctx_mut(), |_| {});
     egui::TopBottomPanel::top("top").exact_height(200.0).show(egui_context.ctx_mut(), |ui| {
@@ -23,10 +47,326 @@ fn main_menu(mut egui_context: ResMut<EguiContext>, mut state: ResMut<State<Game
         });
     });
     egui::CentralPanel::default().show(egui_context.ctx_mut(), |ui| {
-        ui.with_layout(egui::Layout::centered_and_justified(egui::Direction::BottomUp), |ui| {
+        ui.vertical_centered_justified(|ui| {
             if ui.button("New Game").clicked() {
-                state.set(WorldGen).unwrap();
+                state.set(WorldSetup).unwrap();
+            }
+            if ui.button("Load Game").clicked() {
+                // Look for saves once when the list opens rather than every frame.
+                *saves = match saves.take() {
+                    Some(_) => None,
+                    None => Some(list_saves()),
+                };
+                *load_error = None;
+            }
+            if let Some(saves) = saves.as_ref() {
+                ui.separator();
+                if saves.is_empty() {
+                    ui.label(format!("No saved games in {}", SAVE_DIRECTORY));
+                }
+                for path in saves {
+                    let name = path.file_stem().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned());
+                    if ui.button(name).clicked() {
+                        match LoadedGame::read(path, &registry) {
+                            Ok(loaded) => {
+                                commands.insert_resource(loaded);
+                                state.set(WorldGen).unwrap();
+                            }
+                            Err(error) => *load_error = Some(format!("Could not load {}: {}", path.display(), error)),
+                        }
+                    }
+                }
+                if let Some(error) = load_error.as_ref() {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
             }
         })
     });
-}
\ No newline at end of file
+}
+
+fn world_setup(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameState>>,
+    mut settings: ResMut<WorldGenSettings>,
+    mut heightmap_path: Local<String>,
+    mut import_error: Local<Option<String>>)
+    {
+    egui::CentralPanel::default().show(egui_context.ctx_mut(), |ui| {
+        ui.vertical_centered(|ui| {
+            ui.heading("World Setup");
+        });
+        ui.separator();
+
+        let mut preset = settings.preset;
+        egui::ComboBox::from_label("Preset")
+            .selected_text(preset.to_string())
+            .show_ui(ui, |ui| {
+                for option in WorldPreset::iter() {
+                    ui.selectable_value(&mut preset, option, option.to_string());
+                }
+            });
+        if preset != settings.preset {
+            settings.apply_preset(preset);
+        }
+
+        // Work on a copy so that only an actual edit marks the settings as a custom preset.
+        let mut edited = settings.clone();
+        egui::Grid::new("world_setup_grid").num_columns(2).spacing([40.0, 8.0]).show(ui, |ui| {
+            ui.label("Seed");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut edited.seed));
+                if ui.button("Randomize").clicked() {
+                    edited.seed = random_seed();
+                }
+            });
+            ui.end_row();
+
+            ui.label("Layer size");
+            ui.add(egui::Slider::new(&mut edited.layer_size, 16..=512));
+            ui.end_row();
+
+            ui.label("Minimum height");
+            ui.add(egui::DragValue::new(&mut edited.min_height).clamp_range(0..=edited.max_height - 1));
+            ui.end_row();
+
+            ui.label("Maximum height");
+            ui.add(egui::DragValue::new(&mut edited.max_height).clamp_range(edited.min_height + 1..=256));
+            ui.end_row();
+
+            ui.label("Sea level");
+            ui.add(egui::Slider::new(&mut edited.sea_level, edited.min_height..=edited.max_height - 1));
+            ui.end_row();
+
+            ui.label("Noise scaling");
+            ui.add(egui::Slider::new(&mut edited.scaling, 0.005..=0.5).logarithmic(true));
+            ui.end_row();
+
+            ui.label("Heightmap image");
+            ui.add(egui::TextEdit::singleline(&mut *heightmap_path).hint_text("Generate terrain"));
+            ui.end_row();
+        });
+
+        egui::CollapsingHeader::new("Terrain noise").show(ui, |ui| {
+            let terrain = &mut edited.terrain;
+            egui::Grid::new("terrain_noise_grid").num_columns(2).spacing([40.0, 8.0]).show(ui, |ui| {
+                ui.label("Fractal");
+                ui.horizontal(|ui| {
+                    for option in FractalKind::iter() {
+                        ui.selectable_value(&mut terrain.fractal, option, option.to_string());
+                    }
+                });
+                ui.end_row();
+
+                ui.label("Octaves");
+                ui.add(egui::Slider::new(&mut terrain.octaves, 1..=10));
+                ui.end_row();
+
+                ui.label("Lacunarity");
+                ui.add(egui::Slider::new(&mut terrain.lacunarity, 1.0..=4.0));
+                ui.end_row();
+
+                ui.label("Persistence");
+                ui.add(egui::Slider::new(&mut terrain.persistence, 0.1..=0.9));
+                ui.end_row();
+
+                ui.label("Warp strength");
+                ui.add(egui::Slider::new(&mut terrain.warp_strength, 0.0..=32.0));
+                ui.end_row();
+
+                ui.label("Warp scaling");
+                ui.add(egui::Slider::new(&mut terrain.warp_scaling, 0.005..=0.5).logarithmic(true));
+                ui.end_row();
+
+                ui.label("Continent scaling");
+                ui.add(egui::Slider::new(&mut terrain.continent_scaling, 0.001..=0.1).logarithmic(true));
+                ui.end_row();
+
+                ui.label("Continent strength");
+                ui.add(egui::Slider::new(&mut terrain.continent_strength, 0.0..=1.0));
+                ui.end_row();
+            });
+        });
+
+        egui::CollapsingHeader::new("Caves").show(ui, |ui| {
+            let caves = &mut edited.caves;
+            ui.checkbox(&mut caves.enabled, "Generate caves");
+            ui.add_enabled_ui(caves.enabled, |ui| {
+                egui::Grid::new("caves_grid").num_columns(2).spacing([40.0, 8.0]).show(ui, |ui| {
+                    ui.label("Cave scaling");
+                    ui.add(egui::Slider::new(&mut caves.scaling, 0.01..=0.3).logarithmic(true));
+                    ui.end_row();
+
+                    ui.label("Cavern threshold");
+                    ui.add(egui::Slider::new(&mut caves.cavern_threshold, 0.0..=1.0));
+                    ui.end_row();
+
+                    ui.label("Tunnel width");
+                    ui.add(egui::Slider::new(&mut caves.tunnel_width, 0.0..=0.2));
+                    ui.end_row();
+
+                    ui.label("Surface margin");
+                    ui.add(egui::Slider::new(&mut caves.surface_margin, 0..=16));
+                    ui.end_row();
+
+                    ui.label("Lake level");
+                    ui.add(egui::Slider::new(&mut caves.lake_level, 0.0..=1.0));
+                    ui.end_row();
+                });
+            });
+        });
+
+        egui::CollapsingHeader::new("Geology").show(ui, |ui| {
+            let geology = &mut edited.geology;
+            egui::Grid::new("geology_grid").num_columns(2).spacing([40.0, 8.0]).show(ui, |ui| {
+                ui.label("Soil depth");
+                ui.add(egui::Slider::new(&mut geology.soil_depth, 0..=10));
+                ui.end_row();
+
+                ui.label("Metamorphic depth");
+                ui.add(egui::Slider::new(&mut geology.metamorphic_depth, 0.0..=1.0));
+                ui.end_row();
+
+                ui.label("Igneous depth");
+                ui.add(egui::Slider::new(&mut geology.igneous_depth, 0.0..=1.0));
+                ui.end_row();
+
+                ui.label("Ore abundance");
+                ui.add(egui::Slider::new(&mut geology.ore_abundance, 0.0..=3.0));
+                ui.end_row();
+
+                ui.label("Gem abundance");
+                ui.add(egui::Slider::new(&mut geology.gem_abundance, 0.0..=3.0));
+                ui.end_row();
+            });
+        });
+
+        egui::CollapsingHeader::new("Rivers").show(ui, |ui| {
+            let water = &mut edited.water;
+            egui::Grid::new("rivers_grid").num_columns(2).spacing([40.0, 8.0]).show(ui, |ui| {
+                ui.label("River count");
+                ui.add(egui::Slider::new(&mut water.river_count, 0..=32));
+                ui.end_row();
+
+                ui.label("Source height");
+                ui.add(egui::Slider::new(&mut water.river_source_height, 0.0..=1.0));
+                ui.end_row();
+
+                ui.label("River depth");
+                ui.add(egui::Slider::new(&mut water.river_depth, 1..=4));
+                ui.end_row();
+            });
+        });
+
+        egui::CollapsingHeader::new("Erosion").show(ui, |ui| {
+            let erosion = &mut edited.erosion;
+            ui.checkbox(&mut erosion.enabled, "Erode terrain");
+            ui.add_enabled_ui(erosion.enabled, |ui| {
+                egui::Grid::new("erosion_grid").num_columns(2).spacing([40.0, 8.0]).show(ui, |ui| {
+                    ui.label("Droplets");
+                    ui.add(egui::Slider::new(&mut erosion.droplets, 0..=200000).logarithmic(true));
+                    ui.end_row();
+
+                    ui.label("Droplet lifetime");
+                    ui.add(egui::Slider::new(&mut erosion.droplet_lifetime, 1..=100));
+                    ui.end_row();
+
+                    ui.label("Inertia");
+                    ui.add(egui::Slider::new(&mut erosion.inertia, 0.0..=1.0));
+                    ui.end_row();
+
+                    ui.label("Sediment capacity");
+                    ui.add(egui::Slider::new(&mut erosion.sediment_capacity, 0.5..=16.0));
+                    ui.end_row();
+
+                    ui.label("Erode speed");
+                    ui.add(egui::Slider::new(&mut erosion.erode_speed, 0.0..=1.0));
+                    ui.end_row();
+
+                    ui.label("Deposit speed");
+                    ui.add(egui::Slider::new(&mut erosion.deposit_speed, 0.0..=1.0));
+                    ui.end_row();
+
+                    ui.label("Evaporation");
+                    ui.add(egui::Slider::new(&mut erosion.evaporation, 0.0..=0.5));
+                    ui.end_row();
+
+                    ui.label("Thermal iterations");
+                    ui.add(egui::Slider::new(&mut erosion.thermal_iterations, 0..=200));
+                    ui.end_row();
+
+                    ui.label("Talus");
+                    ui.add(egui::Slider::new(&mut erosion.talus, 0.5..=4.0));
+                    ui.end_row();
+                });
+            });
+        });
+
+        egui::CollapsingHeader::new("Climate").show(ui, |ui| {
+            let climate = &mut edited.climate;
+            egui::Grid::new("climate_grid").num_columns(2).spacing([40.0, 8.0]).show(ui, |ui| {
+                ui.label("Climate scaling");
+                ui.add(egui::Slider::new(&mut climate.scaling, 0.002..=0.2).logarithmic(true));
+                ui.end_row();
+
+                ui.label("Temperature");
+                ui.add(egui::Slider::new(&mut climate.temperature_offset, -1.0..=1.0));
+                ui.end_row();
+
+                ui.label("Moisture");
+                ui.add(egui::Slider::new(&mut climate.moisture_offset, -1.0..=1.0));
+                ui.end_row();
+
+                ui.label("Lapse rate");
+                ui.add(egui::Slider::new(&mut climate.lapse_rate, 0.0..=1.0));
+                ui.end_row();
+            });
+        });
+
+        let seed_changed = edited.seed != settings.seed;
+        let terrain_changed = edited.layer_size != settings.layer_size
+            || edited.min_height != settings.min_height
+            || edited.max_height != settings.max_height
+            || edited.sea_level != settings.sea_level
+            || edited.scaling != settings.scaling
+            || edited.terrain != settings.terrain
+            || edited.caves != settings.caves
+            || edited.geology != settings.geology
+            || edited.water != settings.water
+            || edited.erosion != settings.erosion
+            || edited.climate != settings.climate;
+        if terrain_changed {
+            edited.preset = WorldPreset::Custom;
+        }
+        if seed_changed || terrain_changed {
+            *settings = edited;
+        }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            if ui.button("Back").clicked() {
+                state.set(MainMenu).unwrap();
+            }
+            if ui.button("Generate World").clicked() {
+                // An empty path generates the terrain, otherwise the image replaces the generated heightmap.
+                let path = heightmap_path.trim();
+                let imported = if path.is_empty() {
+                    Ok(None)
+                } else {
+                    let height_limits = MapHeight::new(settings.max_height, settings.min_height);
+                    import_heightmap(Path::new(path), settings.layer_size, &height_limits).map(Some)
+                };
+                match imported {
+                    Ok(imported) => {
+                        settings.imported_heightmap = imported;
+                        *import_error = None;
+                        state.set(WorldGen).unwrap();
+                    }
+                    Err(error) => *import_error = Some(format!("Could not import {}: {}", path, error)),
+                }
+            }
+        });
+        if let Some(error) = import_error.as_ref() {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+    });
+}
@@ -1,19 +1,36 @@
-use std::ops::Range;
 use bevy::prelude::*;
 use bevy_ecs_tilemap::prelude::*;
-use noise::{Perlin, NoiseFn};
-use ndarray::{Array2};
+use ndarray::{Array2, Array3};
 use self::height::Height;
+use self::terrain::{TerrainNoise, FractalKind, sample_terrain};
+use self::caves::{CaveSettings, carve_caves};
+use self::geology::{GeologySettings, assign_geology};
+use self::water::{WaterSettings, trace_rivers, fill_water};
+use self::erosion::{ErosionSettings, erode};
+use self::biomes::{Biome, Climate, ClimateSettings, create_climate, apply_biomes};
 pub(crate) mod height;
+pub(crate) mod terrain;
+pub(crate) mod caves;
+pub(crate) mod geology;
+pub(crate) mod water;
+pub(crate) mod erosion;
+pub(crate) mod biomes;
+pub(crate) mod heightmap_image;
 use crate::GameState::{WorldGen, self};
 use crate::texture_loader::TileTextureAtlas;
 use crate::next_game_state;
 use crate::tiles::{GameTilemapSize, GameTilePos, Game3DSize};
-use crate::texture_loader::TileType::*;
+use crate::world3d::World3D;
+use crate::save::LoadedGame;
+use crate::materials::MaterialId;
+use crate::texture_loader::TileType::{self, *};
+use strum::{EnumIter, EnumString, Display};
+use serde::{Deserialize, Serialize};
 
 
 pub struct MapGeneratorPlugin;
 
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct MapHeight{
     pub max: Height,
     pub min: Height,
@@ -25,185 +42,404 @@ impl MapHeight{
             min: Height{value: min}
         }
     } 
-    pub fn iter(self: &Self) -> Range<usize>{
-        self.min.into()..self.max.into()
-    }
     pub fn height_interval(self: &Self) -> Height {
         self.max - self.min
     }
 }
 
+// Every stage of world generation draws its randomness from its own seed derived from the world seed,
+// so adding or reordering a stage never changes what the other stages generate.
+#[derive(Clone, Copy, Debug, EnumIter)]
+pub enum GenerationStage {
+    Heightmap = 0,
+    DomainWarp = 1,
+    Continents = 2,
+    Caverns = 3,
+    Tunnels = 4,
+    CaveLakes = 5,
+    Strata = 6,
+    RockRegions = 7,
+    OreVeins = 8,
+    Gems = 9,
+    Rivers = 10,
+    Erosion = 11,
+    Temperature = 12,
+    Moisture = 13,
+}
+
+pub const TILE_PIXEL_LENGTH: f32 = 256.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, EnumString, Display, Serialize, Deserialize)]
+#[strum(ascii_case_insensitive)]
+pub enum WorldPreset {
+    Small,
+    Standard,
+    Large,
+    Huge,
+    Highlands,
+    Flatlands,
+    Custom,
+}
+
+// What the player picked on the world setup screen, turned into MapSettings when world generation starts.
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct WorldGenSettings {
+    pub preset: WorldPreset,
+    pub seed: u32,
+    pub layer_size: usize,
+    pub max_height: usize,
+    pub min_height: usize,
+    pub sea_level: usize,
+    pub scaling: f64,
+    pub terrain: TerrainNoise,
+    pub caves: CaveSettings,
+    pub geology: GeologySettings,
+    pub water: WaterSettings,
+    pub erosion: ErosionSettings,
+    pub climate: ClimateSettings,
+    // A heightmap imported from an image, used in place of the generated one.
+    #[serde(skip)]
+    pub imported_heightmap: Option<HeightMap>,
+}
+impl Default for WorldGenSettings {
+    fn default() -> Self {
+        let mut settings = Self {
+            preset: WorldPreset::Standard,
+            seed: random_seed(),
+            layer_size: 0,
+            max_height: 0,
+            min_height: 0,
+            sea_level: 0,
+            scaling: 0.0,
+            terrain: TerrainNoise::default(),
+            caves: CaveSettings::default(),
+            geology: GeologySettings::default(),
+            water: WaterSettings::default(),
+            erosion: ErosionSettings::default(),
+            climate: ClimateSettings::default(),
+            imported_heightmap: None,
+        };
+        settings.apply_preset(WorldPreset::Standard);
+        settings
+    }
+}
+impl WorldGenSettings {
+    pub fn apply_preset(self: &mut Self, preset: WorldPreset) {
+        let (layer_size, max_height, min_height, sea_level, scaling) = match preset {
+            WorldPreset::Small => (32, 32, 0, 12, 0.1),
+            WorldPreset::Standard => (64, 64, 0, 24, 0.05),
+            WorldPreset::Large => (128, 64, 0, 24, 0.025),
+            WorldPreset::Huge => (512, 128, 0, 48, 0.0125),
+            WorldPreset::Highlands => (64, 96, 0, 20, 0.04),
+            WorldPreset::Flatlands => (64, 24, 0, 10, 0.02),
+            WorldPreset::Custom => (self.layer_size, self.max_height, self.min_height, self.sea_level, self.scaling),
+        };
+        let terrain = match preset {
+            WorldPreset::Highlands => TerrainNoise {
+                fractal: FractalKind::Ridged,
+                octaves: 6,
+                persistence: 0.55,
+                continent_strength: 0.3,
+                ..Default::default()
+            },
+            WorldPreset::Flatlands => TerrainNoise {
+                octaves: 3,
+                persistence: 0.35,
+                warp_strength: 2.0,
+                continent_strength: 0.7,
+                ..Default::default()
+            },
+            WorldPreset::Custom => self.terrain.clone(),
+            _ => TerrainNoise::default(),
+        };
+        self.preset = preset;
+        self.terrain = terrain;
+        self.layer_size = layer_size;
+        self.max_height = max_height;
+        self.min_height = min_height;
+        self.sea_level = sea_level;
+        self.scaling = scaling;
+    }
+}
+
+pub fn random_seed() -> u32 {
+    rand::random()
+}
+
+pub fn stage_seed(seed: u32, stage: GenerationStage) -> u32 {
+    // SplitMix64 finalizer, good enough to decorrelate neighbouring seeds.
+    let mut value = ((seed as u64) << 32) | stage as u64;
+    value = value.wrapping_add(0x9E3779B97F4A7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D049BB133111EB);
+    value ^= value >> 31;
+    value as u32
+}
+
 #[derive(Resource)]
 pub struct MapSettings {
+    pub seed: u32,
     pub layer_size: GameTilemapSize,
     pub size: Game3DSize,
     pub height_limits: MapHeight,
+    pub sea_level: Height,
     pub scaling: f64,
+    pub terrain: TerrainNoise,
+    pub caves: CaveSettings,
+    pub geology: GeologySettings,
+    pub water: WaterSettings,
+    pub erosion: ErosionSettings,
+    pub climate: ClimateSettings,
     pub tile_size: TilemapTileSize,
     pub heightmap: HeightMap,
+    pub temperature: Array2<f64>,
+    pub moisture: Array2<f64>,
+    pub biomes: Array2<Biome>,
     pub texture_handle: Handle<Image>,
 }
 impl MapSettings {
-    pub fn new(size: GameTilemapSize, height_limits: MapHeight, tile_size: TilemapTileSize, scaling: f64, texture_handle: Handle<Image>) -> Self {
-        let heightmap = create_heightmap(0, &size, &height_limits, scaling);
+    // Runs every generation stage, returning the map description along with the voxels of the generated world.
+    pub fn generate(settings: &WorldGenSettings, tile_size: TilemapTileSize, texture_handle: Handle<Image>) -> (Self, World3D) {
+        let seed = settings.seed;
+        let size = GameTilemapSize::new(settings.layer_size, settings.layer_size);
+        let height_limits = MapHeight::new(settings.max_height, settings.min_height);
+
+        let sea_level = Height{value: settings.sea_level.clamp(settings.min_height, settings.max_height - 1)};
+
+        // An imported heightmap is used as painted, erosion and river beds would reshape it.
+        let (heightmap, rivers) = match &settings.imported_heightmap {
+            Some(heightmap) => (heightmap.clone(), Vec::new()),
+            None => {
+                let mut heightmap = create_heightmap(seed, &size, &height_limits, settings.scaling, &settings.terrain);
+                erode(seed, &mut heightmap, &height_limits, &settings.erosion);
+                let rivers = trace_rivers(seed, &mut heightmap, &height_limits, sea_level, &settings.water);
+                (heightmap, rivers)
+            }
+        };
+        let climate = create_climate(seed, &size, &heightmap, &height_limits, sea_level, &settings.climate);
+        let mut voxels = create_voxels(&heightmap, &height_limits);
+        assign_geology(seed, &mut voxels, &heightmap, &height_limits, &settings.geology);
+        apply_biomes(&mut voxels, &heightmap, &height_limits, &climate.biomes);
+        carve_caves(seed, &mut voxels, &heightmap, &height_limits, &settings.caves);
+        fill_water(&mut voxels, &heightmap, &height_limits, sea_level, &rivers, &settings.water);
+
+        let world = World3D::from_voxels(&voxels, height_limits.min);
+        (Self::from_parts(settings, tile_size, texture_handle, heightmap, climate), world)
+    }
+    // Puts a map description back together from already generated parts, either fresh from generation or from a save.
+    pub fn from_parts(settings: &WorldGenSettings, tile_size: TilemapTileSize, texture_handle: Handle<Image>, heightmap: HeightMap, climate: Climate) -> Self {
+        let size = GameTilemapSize::new(settings.layer_size, settings.layer_size);
+        let height_limits = MapHeight::new(settings.max_height, settings.min_height);
         let size3d = Game3DSize{x: size.size.x as usize, y: size.size.y as usize, z: height_limits.height_interval().into()};
         Self {
+            seed: settings.seed,
             layer_size: size,
             size: size3d,
             height_limits,
-            scaling,
+            sea_level: Height{value: settings.sea_level.clamp(settings.min_height, settings.max_height - 1)},
+            scaling: settings.scaling,
+            terrain: settings.terrain.clone(),
+            caves: settings.caves.clone(),
+            geology: settings.geology.clone(),
+            water: settings.water.clone(),
+            erosion: settings.erosion.clone(),
+            climate: settings.climate.clone(),
             tile_size,
             heightmap,
+            temperature: climate.temperature,
+            moisture: climate.moisture,
+            biomes: climate.biomes,
             texture_handle
         }
     }
+    // The settings this map was generated with. Which preset they came from is not kept, so they always read as custom.
+    pub fn world_gen_settings(self: &Self) -> WorldGenSettings {
+        WorldGenSettings {
+            preset: WorldPreset::Custom,
+            seed: self.seed,
+            layer_size: self.layer_size.size.x as usize,
+            max_height: self.height_limits.max.into(),
+            min_height: self.height_limits.min.into(),
+            sea_level: self.sea_level.into(),
+            scaling: self.scaling,
+            terrain: self.terrain.clone(),
+            caves: self.caves.clone(),
+            geology: self.geology.clone(),
+            water: self.water.clone(),
+            erosion: self.erosion.clone(),
+            climate: self.climate.clone(),
+            imported_heightmap: None,
+        }
+    }
+    // Below the surface every position counts as underground, whatever the column above it looks like.
+    pub fn biome(self: &Self, position: GameTilePos) -> Biome {
+        let column = (position.x, position.y);
+        if position.z < self.heightmap[column].value {
+            Biome::Underground
+        } else {
+            self.biomes[column]
+        }
+    }
 }
 
-type HeightMap = Array2<Height>;
+pub(crate) type HeightMap = Array2<Height>;
+// The material of a tile, None is open air.
+pub type Voxel = Option<MaterialId>;
+// Scratch space for the generation stages, indexed by [x, y, height - min height]. Kept as a World3D afterwards.
+// Generation only places the built in materials, so it still names them by TileType.
+pub(crate) type VoxelMap = Array3<Option<TileType>>;
 
-#[derive(Component)]
-pub struct Open;
 #[derive(Component)]
 pub struct Terrain;
-#[derive(Bundle)]
-struct GameTileBundle {
-    position: GameTilePos,
-    tile_bundle: TileBundle
+// Advances to the game once the world has been generated, the renderer streams it in from there.
+fn finish_world_gen(map_settings: Option<Res<MapSettings>>, game_state: ResMut<State<GameState>>){
+    if map_settings.is_some() {
+        next_game_state(game_state);
+    }
 }
-#[derive(Bundle)]
-struct GameTilemapBundle {
-    height: Height,
-    tilemap_bundle: TilemapBundle
+
+fn create_heightmap(seed: u32, tilemap_size: &GameTilemapSize, height_limits: &MapHeight, map_scaling: f64, terrain: &TerrainNoise) -> HeightMap {
+    let elevation = sample_terrain(seed, tilemap_size, map_scaling, terrain);
+    let scaling_amount: f64 = (height_limits.height_interval() - 1).into();
+    elevation.mapv(|value| height_limits.min + (value * scaling_amount) as usize)
 }
-#[derive(Resource)]
-pub struct Tilemap3D {
-    pub layers: Vec<Entity>
+
+fn create_voxels(heightmap: &HeightMap, height_limits: &MapHeight) -> VoxelMap {
+    let (x_size, y_size) = heightmap.dim();
+    let min: usize = height_limits.min.into();
+    Array3::from_shape_fn((x_size, y_size, height_limits.height_interval().into()), |(x, y, z)| {
+        let surface: usize = heightmap[[x, y]].into();
+        if z + min > surface {
+            None
+        } else if z + min == surface {
+            Some(Grass)
+        } else {
+            Some(Stone)
+        }
+    })
 }
 
-pub fn create_tilemap3d(
-    mut commands: Commands,
-    map_settings: Option<Res<MapSettings>>,
-    game_state: ResMut<State<GameState>>,
-    mut tilemap3d: ResMut<Tilemap3D>,
-    texture_atlas: Res<TileTextureAtlas>){
-    if map_settings.is_none() {
-        return;
+fn initalize_resources(mut commands: Commands, texture_handles: Option<Res<TileTextureAtlas>>, settings: Res<WorldGenSettings>, mut loaded: Option<ResMut<LoadedGame>>){
+    // Headless runs never load textures, their maps just carry an empty handle.
+    let texture_handle = texture_handles.and_then(|handles| handles.atlas.as_ref().map(|atlas| atlas.texture.clone())).unwrap_or_default();
+    let tile_size = TilemapTileSize{x: TILE_PIXEL_LENGTH, y: TILE_PIXEL_LENGTH };
+
+    // A loaded game brings its own world, generation only runs for new games.
+    let loaded_world = loaded.as_mut().and_then(|loaded| loaded.world.take());
+    let (map_settings, world) = match (loaded, loaded_world) {
+        (Some(loaded), Some(world)) => {
+            info!("Loaded world seed: {}", loaded.map.settings.seed);
+            let map_settings = MapSettings::from_parts(&loaded.map.settings, tile_size, texture_handle, loaded.map.heightmap.clone(), loaded.climate());
+            (map_settings, world)
+        }
+        _ => {
+            info!("World seed: {}", settings.seed);
+            MapSettings::generate(&settings, tile_size, texture_handle)
+        }
+    };
+    commands.insert_resource(map_settings);
+    commands.insert_resource(world);
+}
+
+impl Plugin for MapGeneratorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<WorldGenSettings>()
+        .add_system_set(
+            SystemSet::on_enter(WorldGen)
+                .with_system(initalize_resources)
+        ).add_system_set(
+            SystemSet::on_update(WorldGen)
+                .with_system(finish_world_gen)
+        );
     }
-    let map_settings = map_settings.unwrap();
+}
 
-    for height in map_settings.height_limits.iter() {
-        let height = Height{value: height};
-        tilemap3d.layers.push(create_tilemap(&mut commands, height, &map_settings, &texture_atlas));
+// A small generated world shared by the tests of everything that works on one.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    pub fn generate(seed: u32) -> (MapSettings, World3D) {
+        generate_preset(WorldPreset::Small, seed)
     }
 
-    next_game_state(game_state);
-}
+    pub fn generate_preset(preset: WorldPreset, seed: u32) -> (MapSettings, World3D) {
+        let mut settings = WorldGenSettings::default();
+        settings.apply_preset(preset);
+        settings.seed = seed;
+        let tile_size = TilemapTileSize { x: TILE_PIXEL_LENGTH, y: TILE_PIXEL_LENGTH };
+        MapSettings::generate(&settings, tile_size, Handle::default())
+    }
 
-fn create_tilemap(commands: &mut Commands, height: Height, map_settings: &MapSettings, texture_atlas: &TileTextureAtlas) -> Entity{
-    let tilemap_entity = commands.spawn_empty().id();
+    pub fn voxels(world: &World3D) -> Vec<Voxel> {
+        world.chunk_positions().flat_map(|chunk_pos| world.chunk_voxels(chunk_pos)).collect()
+    }
+}
 
-    let mut tile_storage = TileStorage::empty(map_settings.layer_size.into());
-    fill_tilemap(commands, tilemap_entity, &mut tile_storage, height, &map_settings, &texture_atlas);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::fixtures::{generate, generate_preset, voxels};
+    use strum::IntoEnumIterator;
 
-    let grid_size = map_settings.tile_size.into();
-    let map_type = TilemapType::default();
+    // A seed has to keep naming the same world, so these must never change between builds.
+    #[test]
+    fn stage_seeds_are_stable() {
+        assert_eq!(stage_seed(42, GenerationStage::Heightmap), 3198934971);
+        assert_eq!(stage_seed(7, GenerationStage::Heightmap), 1133140305);
+    }
 
-    commands.entity(tilemap_entity).insert(GameTilemapBundle {
-        height: height,
-        tilemap_bundle: TilemapBundle {
-            grid_size,
-            map_type,
-            size: map_settings.layer_size.into(),
-            storage: tile_storage,
-            texture: TilemapTexture::Single(map_settings.texture_handle.clone()),
-            tile_size: map_settings.tile_size,
-            transform: get_tilemap_center_transform(&map_settings.layer_size.into(), &grid_size, &map_type, 0.0),
-            ..Default::default()
-        }
-    });
-    tilemap_entity
-}
-
-fn fill_tilemap(
-    commands: &mut Commands,
-    tilemap_entity: Entity,
-    tile_storage: &mut TileStorage,
-    height: Height,
-    map_settings: &MapSettings,
-    texture_atlas: &TileTextureAtlas) {
-    for coordinate in map_settings.layer_size {
-        //println!("{:?}", coordinate);
-        let (x, y) = coordinate;
-        let tile_pos: GameTilePos = [x, y, height.value].into();
-        let tile_2d_pos = tile_pos.into();
-        let mut tile_bundle = GameTileBundle {
-            position: tile_pos,
-            tile_bundle: TileBundle {
-                texture_index: texture_atlas.indices[Shadow],
-                position: tile_2d_pos,
-                tilemap_id: TilemapId(tilemap_entity),
-                //color: TileColor(Color::rgba_u8(255, height.value as u8, 255, 255)),
-                visible: TileVisible(false),
-                ..Default::default()
+    #[test]
+    fn stage_seeds_differ_between_stages_and_seeds() {
+        let stages: Vec<GenerationStage> = GenerationStage::iter().collect();
+        for seed in [0, 1, 42, u32::MAX] {
+            for (index, stage) in stages.iter().enumerate() {
+                for other in &stages[index + 1..] {
+                    assert_ne!(stage_seed(seed, *stage), stage_seed(seed, *other), "seed {} {:?} {:?}", seed, stage, other);
+                }
+                assert_ne!(stage_seed(seed, *stage), stage_seed(seed.wrapping_add(1), *stage), "seed {} {:?}", seed, stage);
             }
-        };
-        let tile_entity = if height > map_settings.heightmap[coordinate] {
-            continue;
-            /* commands
-            .spawn((tile_bundle, Open)) */
-        } else if height != map_settings.heightmap[coordinate] {
-            tile_bundle.tile_bundle.texture_index = texture_atlas.indices[Stone];
-            commands
-            .spawn((tile_bundle, Terrain))
-        } else {
-            tile_bundle.tile_bundle.texture_index = texture_atlas.indices[Grass];
-            commands
-            .spawn((tile_bundle, Terrain))
-        };
-        
-        tile_storage.set(&tile_2d_pos, tile_entity.id());
+        }
     }
-}
 
-fn create_heightmap(seed: u32, tilemap_size: &GameTilemapSize, height_limits: &MapHeight, map_scaling: f64) -> HeightMap {
-    let noise = Perlin::new(seed);
-    let shape: [usize; 2] = (*tilemap_size).into();
-    let mut heightmap = Array2::from_elem(shape, Height{value: 0});
-    let scaling_amount: f64 = (height_limits.max - height_limits.min).into();
-    for (x, y) in tilemap_size.into_iter() {
-        let noise_value = noise.get([x as f64 * map_scaling, y as f64 * map_scaling]);
-        let scaled_noise = ((noise_value + 1.0) / 2.0) * scaling_amount;
-        heightmap[[x as usize, y as usize]] = Height{value: scaled_noise as usize};
+    #[test]
+    fn same_seed_generates_the_same_world() {
+        let (first_map, first_world) = generate(1234);
+        let (second_map, second_world) = generate(1234);
+        assert_eq!(first_map.heightmap, second_map.heightmap);
+        assert_eq!(first_map.temperature, second_map.temperature);
+        assert_eq!(first_map.moisture, second_map.moisture);
+        assert_eq!(first_map.biomes, second_map.biomes);
+        assert_eq!(voxels(&first_world), voxels(&second_world));
     }
-    heightmap
-}
 
-fn initalize_resources(mut commands: Commands, texture_handles: Res<TileTextureAtlas>){
-    let max_height = 64;
-    let min_height = 0;
-    let tile_pixel_length = 256.0;
-    let tile_map_size = 64;
-    let scaling = 0.1;
-    let texture_handle = texture_handles.atlas.as_ref().unwrap().texture.clone();
+    #[test]
+    fn different_seeds_generate_different_worlds() {
+        let (first_map, first_world) = generate(1234);
+        let (second_map, second_world) = generate(4321);
+        assert_ne!(first_map.heightmap, second_map.heightmap);
+        assert_ne!(voxels(&first_world), voxels(&second_world));
+    }
 
-    commands.insert_resource(MapSettings::new(
-        GameTilemapSize::new(tile_map_size, tile_map_size),
-        MapHeight::new(max_height, min_height),
-        TilemapTileSize{x: tile_pixel_length, y: tile_pixel_length },
-        scaling,
-        texture_handle
-    ));
-    commands.insert_resource(Tilemap3D{layers: Vec::new()});
-}
+    #[test]
+    fn standard_worlds_rise_above_sea_level() {
+        for seed in [1, 7, 42, 99] {
+            let (map_settings, _) = generate_preset(WorldPreset::Standard, seed);
+            let highest = map_settings.heightmap.iter().max().unwrap().value;
+            let land = map_settings.heightmap.iter().filter(|height| **height > map_settings.sea_level).count();
+            assert!(land * 4 >= map_settings.heightmap.len(), "seed {} is mostly ocean", seed);
+            assert!(highest >= map_settings.height_limits.max.value * 2 / 3, "seed {} only reaches height {}", seed, highest);
+        }
+    }
 
-impl Plugin for MapGeneratorPlugin {
-    fn build(&self, app: &mut App) {
-        app.add_system_set(
-            SystemSet::on_enter(WorldGen)
-                .with_system(initalize_resources)
-        ).add_system_set(
-            SystemSet::on_update(WorldGen)
-                .with_system(create_tilemap3d)
-        );
+    #[test]
+    fn imported_heightmaps_are_kept_as_painted() {
+        let mut settings = WorldGenSettings::default();
+        settings.apply_preset(WorldPreset::Small);
+        let heightmap = HeightMap::from_shape_fn([settings.layer_size, settings.layer_size], |(x, y)| Height { value: (x + y) % settings.max_height });
+        settings.imported_heightmap = Some(heightmap.clone());
+        let tile_size = TilemapTileSize { x: TILE_PIXEL_LENGTH, y: TILE_PIXEL_LENGTH };
+        let (map_settings, _) = MapSettings::generate(&settings, tile_size, Handle::default());
+        assert_eq!(map_settings.heightmap, heightmap);
     }
-}
\ No newline at end of file
+}
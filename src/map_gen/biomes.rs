@@ -0,0 +1,165 @@
+use ndarray::Array2;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+use strum::{Display, EnumIter};
+use serde::{Deserialize, Serialize};
+
+use crate::texture_loader::TileType::{self, *};
+use crate::tiles::GameTilemapSize;
+
+use super::height::Height;
+use super::{stage_seed, GenerationStage, HeightMap, MapHeight, VoxelMap};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, EnumIter, Display, Serialize, Deserialize)]
+pub enum Biome {
+    Ocean,
+    Beach,
+    Tundra,
+    Taiga,
+    Grassland,
+    Forest,
+    Swamp,
+    Desert,
+    Savanna,
+    Jungle,
+    Mountain,
+    SnowyPeaks,
+    Underground,
+}
+impl Biome {
+    pub fn surface(self: &Self) -> TileType {
+        match self {
+            Biome::Ocean | Biome::Beach | Biome::Desert => Sand,
+            Biome::Tundra | Biome::SnowyPeaks => Snow,
+            Biome::Taiga | Biome::Grassland | Biome::Forest | Biome::Jungle => Grass,
+            Biome::Swamp => Mud,
+            Biome::Savanna => DryGrass,
+            Biome::Mountain | Biome::Underground => Stone,
+        }
+    }
+    pub fn subsurface(self: &Self) -> TileType {
+        match self {
+            Biome::Ocean | Biome::Beach | Biome::Desert => Sand,
+            Biome::Swamp | Biome::Jungle => Mud,
+            Biome::Mountain | Biome::SnowyPeaks | Biome::Underground => Stone,
+            _ => Soil,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClimateSettings {
+    pub scaling: f64,
+    // Shifts the whole world warmer or colder, wetter or drier, -1..1.
+    pub temperature_offset: f64,
+    pub moisture_offset: f64,
+    // How much colder the land gets from the sea level to the highest peak, 0..1.
+    pub lapse_rate: f64,
+}
+impl Default for ClimateSettings {
+    fn default() -> Self {
+        Self {
+            scaling: 0.02,
+            temperature_offset: 0.0,
+            moisture_offset: 0.0,
+            lapse_rate: 0.5,
+        }
+    }
+}
+
+pub struct Climate {
+    pub temperature: Array2<f64>,
+    pub moisture: Array2<f64>,
+    pub biomes: Array2<Biome>,
+}
+
+const WATER_REACH: isize = 3;
+
+fn unit_noise(noise: &Fbm<Perlin>, x: f64, y: f64) -> f64 {
+    ((noise.get([x, y]) + 1.0) / 2.0).clamp(0.0, 1.0)
+}
+
+pub fn classify(temperature: f64, moisture: f64, elevation: f64, surface: Height, sea_level: Height) -> Biome {
+    if surface < sea_level {
+        return Biome::Ocean;
+    }
+    if surface <= sea_level + 1 && temperature > 0.25 {
+        return Biome::Beach;
+    }
+    if elevation > 0.8 {
+        return if temperature < 0.4 { Biome::SnowyPeaks } else { Biome::Mountain };
+    }
+    if temperature < 0.25 {
+        Biome::Tundra
+    } else if temperature < 0.45 {
+        if moisture < 0.4 { Biome::Tundra } else { Biome::Taiga }
+    } else if temperature < 0.7 {
+        if moisture < 0.3 {
+            Biome::Grassland
+        } else if moisture < 0.6 {
+            Biome::Forest
+        } else {
+            Biome::Swamp
+        }
+    } else if moisture < 0.3 {
+        Biome::Desert
+    } else if moisture < 0.55 {
+        Biome::Savanna
+    } else {
+        Biome::Jungle
+    }
+}
+
+pub fn create_climate(seed: u32, tilemap_size: &GameTilemapSize, heightmap: &HeightMap, height_limits: &MapHeight, sea_level: Height, settings: &ClimateSettings) -> Climate {
+    let temperature_noise = Fbm::<Perlin>::new(stage_seed(seed, GenerationStage::Temperature)).set_octaves(3);
+    let moisture_noise = Fbm::<Perlin>::new(stage_seed(seed, GenerationStage::Moisture)).set_octaves(4);
+    let shape: [usize; 2] = (*tilemap_size).into();
+    let (width, length) = (shape[0] as isize, shape[1] as isize);
+    let sea: f64 = sea_level.into();
+    let top: f64 = (height_limits.max - 1).into();
+    let min: f64 = height_limits.min.into();
+
+    let mut temperature = Array2::zeros(shape);
+    let mut moisture = Array2::zeros(shape);
+    let mut biomes = Array2::from_elem(shape, Biome::Grassland);
+    for (x, y) in tilemap_size.into_iter() {
+        let (x_f, y_f) = (x as f64 * settings.scaling, y as f64 * settings.scaling);
+        let height: f64 = heightmap[[x, y]].into();
+        let above_sea = ((height - sea) / (top - sea).max(1.0)).clamp(0.0, 1.0);
+        let elevation = ((height - min) / (top - min).max(1.0)).clamp(0.0, 1.0);
+
+        // Warmest along the middle row of the map, like an equator, and colder the higher the ground.
+        let latitude = 1.0 - ((y as f64 / length.max(1) as f64) - 0.5).abs() * 2.0;
+        let heat = latitude * 0.5 + unit_noise(&temperature_noise, x_f, y_f) * 0.5 - above_sea * settings.lapse_rate + settings.temperature_offset;
+
+        let near_water = (-WATER_REACH..=WATER_REACH).any(|dx| (-WATER_REACH..=WATER_REACH).any(|dy| {
+            let (nx, ny) = (x as isize + dx, y as isize + dy);
+            nx >= 0 && ny >= 0 && nx < width && ny < length && heightmap[[nx as usize, ny as usize]] < sea_level
+        }));
+        let wetness = unit_noise(&moisture_noise, x_f, y_f) + if near_water { 0.2 } else { 0.0 } + settings.moisture_offset;
+
+        temperature[[x, y]] = heat.clamp(0.0, 1.0);
+        moisture[[x, y]] = wetness.clamp(0.0, 1.0);
+        biomes[[x, y]] = classify(temperature[[x, y]], moisture[[x, y]], elevation, heightmap[[x, y]], sea_level);
+    }
+
+    Climate {
+        temperature,
+        moisture,
+        biomes,
+    }
+}
+
+// Dresses each column in its biome: the surface tile on top, and the biome's subsurface in place of plain soil.
+pub fn apply_biomes(voxels: &mut VoxelMap, heightmap: &HeightMap, height_limits: &MapHeight, biomes: &Array2<Biome>) {
+    let min: usize = height_limits.min.into();
+    for ((x, y), surface) in heightmap.indexed_iter() {
+        let surface: usize = (*surface).into();
+        let biome = biomes[[x, y]];
+        voxels[[x, y, surface - min]] = Some(biome.surface());
+        for z in min..surface {
+            if voxels[[x, y, z - min]] == Some(Soil) {
+                voxels[[x, y, z - min]] = Some(biome.subsurface());
+            }
+        }
+    }
+}
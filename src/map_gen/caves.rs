@@ -0,0 +1,69 @@
+use noise::{NoiseFn, Perlin};
+use serde::{Deserialize, Serialize};
+
+use crate::texture_loader::TileType::*;
+
+use super::{stage_seed, GenerationStage, HeightMap, MapHeight, VoxelMap};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CaveSettings {
+    pub enabled: bool,
+    pub scaling: f64,
+    // Caverns open up wherever the cavern noise rises above this threshold.
+    pub cavern_threshold: f64,
+    // Tunnels follow the zero crossings of two noise fields, this is how far from zero still counts.
+    pub tunnel_width: f64,
+    // Number of solid layers kept between the caves and the surface.
+    pub surface_margin: usize,
+    // Fraction of the underground, from the bottom up, that caves may flood to.
+    pub lake_level: f64,
+}
+impl Default for CaveSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            scaling: 0.08,
+            cavern_threshold: 0.45,
+            tunnel_width: 0.06,
+            surface_margin: 3,
+            lake_level: 0.3,
+        }
+    }
+}
+
+pub fn carve_caves(seed: u32, voxels: &mut VoxelMap, heightmap: &HeightMap, height_limits: &MapHeight, settings: &CaveSettings) {
+    if !settings.enabled {
+        return;
+    }
+    let caverns = Perlin::new(stage_seed(seed, GenerationStage::Caverns));
+    let tunnel_seed = stage_seed(seed, GenerationStage::Tunnels);
+    let tunnels_a = Perlin::new(tunnel_seed);
+    let tunnels_b = Perlin::new(tunnel_seed.wrapping_add(1));
+    let water_table = Perlin::new(stage_seed(seed, GenerationStage::CaveLakes));
+
+    let scaling = settings.scaling;
+    let min: usize = height_limits.min.into();
+    let interval: f64 = height_limits.height_interval().into();
+
+    for ((x, y), surface) in heightmap.indexed_iter() {
+        let surface: usize = (*surface).into();
+        // The bottom layer stays solid so nothing can fall out of the world.
+        let top = surface.saturating_sub(settings.surface_margin);
+        let table = water_table.get([x as f64 * scaling * 0.5, y as f64 * scaling * 0.5]);
+        let lake_height = min as f64 + interval * settings.lake_level * (1.0 + table * 0.5);
+
+        for z in (min + 1)..top {
+            // Squash the vertical axis so caverns spread out sideways like real cave systems.
+            let point = [x as f64 * scaling, y as f64 * scaling, z as f64 * scaling * 2.0];
+            let cavern = caverns.get(point);
+            let tunnel = tunnels_a.get(point).abs() < settings.tunnel_width && tunnels_b.get(point).abs() < settings.tunnel_width;
+            if cavern > settings.cavern_threshold || tunnel {
+                voxels[[x, y, z - min]] = if (z as f64) < lake_height && cavern > settings.cavern_threshold {
+                    Some(Water)
+                } else {
+                    None
+                };
+            }
+        }
+    }
+}
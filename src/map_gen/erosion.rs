@@ -0,0 +1,175 @@
+use ndarray::Array2;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use super::{stage_seed, GenerationStage, HeightMap, MapHeight};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErosionSettings {
+    pub enabled: bool,
+    // Number of raindrops simulated by the hydraulic pass.
+    pub droplets: usize,
+    pub droplet_lifetime: usize,
+    // How much a droplet keeps its direction instead of following the slope, 0..1.
+    pub inertia: f64,
+    pub sediment_capacity: f64,
+    pub erode_speed: f64,
+    pub deposit_speed: f64,
+    pub evaporation: f64,
+    pub thermal_iterations: usize,
+    // Steepest height difference between neighbours, in layers, before material slides down.
+    pub talus: f64,
+}
+impl Default for ErosionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            droplets: 4000,
+            droplet_lifetime: 30,
+            inertia: 0.05,
+            sediment_capacity: 4.0,
+            erode_speed: 0.3,
+            deposit_speed: 0.3,
+            evaporation: 0.02,
+            thermal_iterations: 20,
+            talus: 1.5,
+        }
+    }
+}
+
+const GRAVITY: f64 = 4.0;
+const MIN_SEDIMENT_CAPACITY: f64 = 0.01;
+
+// Bilinear height and gradient at a point between four columns.
+fn height_and_gradient(heights: &Array2<f64>, x: f64, y: f64) -> (f64, f64, f64) {
+    let (cell_x, cell_y) = (x as usize, y as usize);
+    let (offset_x, offset_y) = (x - cell_x as f64, y - cell_y as f64);
+
+    let north_west = heights[[cell_x, cell_y]];
+    let north_east = heights[[cell_x + 1, cell_y]];
+    let south_west = heights[[cell_x, cell_y + 1]];
+    let south_east = heights[[cell_x + 1, cell_y + 1]];
+
+    let gradient_x = (north_east - north_west) * (1.0 - offset_y) + (south_east - south_west) * offset_y;
+    let gradient_y = (south_west - north_west) * (1.0 - offset_x) + (south_east - north_east) * offset_x;
+    let height = north_west * (1.0 - offset_x) * (1.0 - offset_y)
+        + north_east * offset_x * (1.0 - offset_y)
+        + south_west * (1.0 - offset_x) * offset_y
+        + south_east * offset_x * offset_y;
+    (height, gradient_x, gradient_y)
+}
+
+// Spreads a height change over the four columns around a point, weighted by how close the point is to each.
+fn apply_bilinear(heights: &mut Array2<f64>, x: f64, y: f64, amount: f64) {
+    let (cell_x, cell_y) = (x as usize, y as usize);
+    let (offset_x, offset_y) = (x - cell_x as f64, y - cell_y as f64);
+    heights[[cell_x, cell_y]] += amount * (1.0 - offset_x) * (1.0 - offset_y);
+    heights[[cell_x + 1, cell_y]] += amount * offset_x * (1.0 - offset_y);
+    heights[[cell_x, cell_y + 1]] += amount * (1.0 - offset_x) * offset_y;
+    heights[[cell_x + 1, cell_y + 1]] += amount * offset_x * offset_y;
+}
+
+fn hydraulic_erosion(rng: &mut StdRng, heights: &mut Array2<f64>, settings: &ErosionSettings) {
+    let (width, length) = heights.dim();
+    if width < 2 || length < 2 {
+        return;
+    }
+    let (max_x, max_y) = ((width - 1) as f64, (length - 1) as f64);
+
+    for _ in 0..settings.droplets {
+        let mut x = rng.gen_range(0.0..max_x);
+        let mut y = rng.gen_range(0.0..max_y);
+        let (mut direction_x, mut direction_y) = (0.0, 0.0);
+        let mut speed = 1.0;
+        let mut water = 1.0;
+        let mut sediment = 0.0;
+
+        for _ in 0..settings.droplet_lifetime {
+            let (height, gradient_x, gradient_y) = height_and_gradient(heights, x, y);
+
+            direction_x = direction_x * settings.inertia - gradient_x * (1.0 - settings.inertia);
+            direction_y = direction_y * settings.inertia - gradient_y * (1.0 - settings.inertia);
+            let norm: f64 = (direction_x * direction_x + direction_y * direction_y).sqrt();
+            if norm < f64::EPSILON {
+                break;
+            }
+            direction_x /= norm;
+            direction_y /= norm;
+
+            let (new_x, new_y) = (x + direction_x, y + direction_y);
+            if new_x < 0.0 || new_y < 0.0 || new_x >= max_x || new_y >= max_y {
+                break;
+            }
+
+            let (new_height, _, _) = height_and_gradient(heights, new_x, new_y);
+            let height_difference = new_height - height;
+            let capacity = (-height_difference * speed * water * settings.sediment_capacity).max(MIN_SEDIMENT_CAPACITY);
+
+            if sediment > capacity || height_difference > 0.0 {
+                // Going uphill fills the pit behind the droplet, otherwise drop what it can no longer carry.
+                let deposit = if height_difference > 0.0 {
+                    height_difference.min(sediment)
+                } else {
+                    (sediment - capacity) * settings.deposit_speed
+                };
+                sediment -= deposit;
+                apply_bilinear(heights, x, y, deposit);
+            } else {
+                // Never dig deeper than the step down, or the droplet would carve a pit it cannot leave.
+                let erosion = ((capacity - sediment) * settings.erode_speed).min(-height_difference);
+                sediment += erosion;
+                apply_bilinear(heights, x, y, -erosion);
+            }
+
+            speed = (speed * speed - height_difference * GRAVITY).max(0.0).sqrt();
+            water *= 1.0 - settings.evaporation;
+            x = new_x;
+            y = new_y;
+        }
+    }
+}
+
+fn thermal_erosion(heights: &mut Array2<f64>, settings: &ErosionSettings) {
+    let (width, length) = heights.dim();
+    let mut changes = Array2::<f64>::zeros((width, length));
+
+    for _ in 0..settings.thermal_iterations {
+        changes.fill(0.0);
+        for x in 0..width {
+            for y in 0..length {
+                let height = heights[[x, y]];
+                for (neighbour_x, neighbour_y) in [(x + 1, y), (x, y + 1)] {
+                    if neighbour_x >= width || neighbour_y >= length {
+                        continue;
+                    }
+                    // Move part of the excess over the talus slope from the higher column to the lower one.
+                    let difference = height - heights[[neighbour_x, neighbour_y]];
+                    if difference.abs() <= settings.talus {
+                        continue;
+                    }
+                    let moved = (difference.abs() - settings.talus) * 0.25 * difference.signum();
+                    changes[[x, y]] -= moved;
+                    changes[[neighbour_x, neighbour_y]] += moved;
+                }
+            }
+        }
+        *heights += &changes;
+    }
+}
+
+pub fn erode(seed: u32, heightmap: &mut HeightMap, height_limits: &MapHeight, settings: &ErosionSettings) {
+    if !settings.enabled {
+        return;
+    }
+    let mut rng = StdRng::seed_from_u64(stage_seed(seed, GenerationStage::Erosion) as u64);
+    let min: f64 = height_limits.min.into();
+    let max: f64 = (height_limits.max - 1).into();
+
+    let mut heights = heightmap.mapv(f64::from);
+    hydraulic_erosion(&mut rng, &mut heights, settings);
+    thermal_erosion(&mut heights, settings);
+
+    heightmap.zip_mut_with(&heights, |height, eroded| {
+        height.value = eroded.round().clamp(min, max) as usize;
+    });
+}
@@ -0,0 +1,130 @@
+use noise::{NoiseFn, Perlin};
+use serde::{Deserialize, Serialize};
+
+use crate::texture_loader::TileType::{self, *};
+
+use super::{stage_seed, GenerationStage, HeightMap, MapHeight, VoxelMap};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RockClass {
+    Soil,
+    Sedimentary,
+    Metamorphic,
+    Igneous,
+}
+
+struct Vein {
+    ore: TileType,
+    hosts: &'static [RockClass],
+    scaling: f64,
+    // Veins follow the zero crossing of a noise field, a wider band means a richer deposit.
+    width: f64,
+}
+
+const VEINS: [Vein; 4] = [
+    Vein { ore: Coal, hosts: &[RockClass::Sedimentary], scaling: 0.06, width: 0.035 },
+    Vein { ore: IronOre, hosts: &[RockClass::Sedimentary, RockClass::Metamorphic], scaling: 0.08, width: 0.025 },
+    Vein { ore: CopperOre, hosts: &[RockClass::Metamorphic, RockClass::Igneous], scaling: 0.08, width: 0.02 },
+    Vein { ore: GoldOre, hosts: &[RockClass::Igneous], scaling: 0.1, width: 0.012 },
+];
+
+const SEDIMENTARY: [TileType; 3] = [Sandstone, Limestone, Shale];
+const METAMORPHIC: [TileType; 2] = [Marble, Slate];
+const IGNEOUS: [TileType; 2] = [Granite, Basalt];
+const GEMS: [TileType; 2] = [Amethyst, Emerald];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GeologySettings {
+    pub soil_depth: usize,
+    // Fractions of the underground depth where the metamorphic and igneous layers begin.
+    pub metamorphic_depth: f64,
+    pub igneous_depth: f64,
+    pub ore_abundance: f64,
+    pub gem_abundance: f64,
+}
+impl Default for GeologySettings {
+    fn default() -> Self {
+        Self {
+            soil_depth: 3,
+            metamorphic_depth: 0.35,
+            igneous_depth: 0.65,
+            ore_abundance: 1.0,
+            gem_abundance: 1.0,
+        }
+    }
+}
+
+fn pick(choices: &[TileType], noise_value: f64) -> TileType {
+    let index = (((noise_value + 1.0) / 2.0) * choices.len() as f64) as usize;
+    choices[index.min(choices.len() - 1)]
+}
+
+// Replaces the plain stone below the surface with soil, rock strata, ore veins and gem clusters.
+pub fn assign_geology(seed: u32, voxels: &mut VoxelMap, heightmap: &HeightMap, height_limits: &MapHeight, settings: &GeologySettings) {
+    let strata = Perlin::new(stage_seed(seed, GenerationStage::Strata));
+    let regions = Perlin::new(stage_seed(seed, GenerationStage::RockRegions));
+    let ore_seed = stage_seed(seed, GenerationStage::OreVeins);
+    let ores: Vec<Perlin> = (0..VEINS.len()).map(|index| Perlin::new(ore_seed.wrapping_add(index as u32))).collect();
+    let gems = Perlin::new(stage_seed(seed, GenerationStage::Gems));
+
+    let min: usize = height_limits.min.into();
+    let interval: f64 = height_limits.height_interval().into();
+
+    for ((x, y), surface) in heightmap.indexed_iter() {
+        let surface: usize = (*surface).into();
+        let (x_f, y_f) = (x as f64, y as f64);
+        let region = regions.get([x_f * 0.02, y_f * 0.02]);
+        let soil_depth = settings.soil_depth as f64 * (1.0 + strata.get([x_f * 0.1, y_f * 0.1]) * 0.5);
+
+        for z in min..surface {
+            let index = [x, y, z - min];
+            if voxels[index] != Some(Stone) {
+                continue;
+            }
+            let depth = (surface - z) as f64;
+            let z_f = z as f64;
+            // Bend the layer boundaries so strata fold instead of lying perfectly flat.
+            let fold = strata.get([x_f * 0.03, y_f * 0.03, z_f * 0.05]) * interval * 0.1;
+            let relative_depth = ((depth + fold) / interval).clamp(0.0, 1.0);
+
+            let class = if depth <= soil_depth {
+                RockClass::Soil
+            } else if relative_depth < settings.metamorphic_depth {
+                RockClass::Sedimentary
+            } else if relative_depth < settings.igneous_depth {
+                RockClass::Metamorphic
+            } else {
+                RockClass::Igneous
+            };
+
+            let band = strata.get([x_f * 0.01, y_f * 0.01, (z_f + fold) * 0.15]);
+            let mut material = match class {
+                RockClass::Soil => Soil,
+                RockClass::Sedimentary => pick(&SEDIMENTARY, band),
+                RockClass::Metamorphic => pick(&METAMORPHIC, region),
+                RockClass::Igneous => pick(&IGNEOUS, (region + band) / 2.0),
+            };
+
+            let point = [x_f, y_f, z_f];
+            for (vein, noise) in VEINS.iter().zip(ores.iter()) {
+                if !vein.hosts.contains(&class) {
+                    continue;
+                }
+                let value = noise.get([point[0] * vein.scaling, point[1] * vein.scaling, point[2] * vein.scaling]);
+                if value.abs() < vein.width * settings.ore_abundance {
+                    material = vein.ore;
+                    break;
+                }
+            }
+
+            if class == RockClass::Igneous || class == RockClass::Metamorphic {
+                let cluster = gems.get([x_f * 0.3, y_f * 0.3, z_f * 0.3]);
+                if cluster > 0.8 - 0.1 * settings.gem_abundance {
+                    material = pick(&GEMS, region);
+                }
+            }
+
+            voxels[index] = Some(material);
+        }
+    }
+}
@@ -1,8 +1,9 @@
 use std::ops::{Sub, Add};
 
 use bevy::prelude::Component;
+use serde::{Deserialize, Serialize};
 
-#[derive(Component, Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Component, Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Height {
     pub value: usize,
 }
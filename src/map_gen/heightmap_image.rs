@@ -0,0 +1,180 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::{Res, ResMut, Local};
+use bevy_egui::{egui, EguiContext};
+use image::{ImageBuffer, ImageError, Luma, Rgb, RgbImage};
+
+use crate::materials::MaterialRegistry;
+use crate::tiles::GameTilePos;
+use crate::world3d::World3D;
+
+use super::height::Height;
+use super::{HeightMap, MapHeight, MapSettings};
+
+// Each height is one step of this size in a 16 bit image. Widening an 8 bit gray level to 16 bits multiplies it
+// by 257 as well, so an 8 bit image painted with gray level 40 reads back as height 40, and heights up to 255 fit.
+const HEIGHT_STEP: u16 = 257;
+pub const EXPORT_DIRECTORY: &str = "exports";
+
+#[derive(Debug)]
+pub enum HeightmapImageError {
+    Image(ImageError),
+    WrongSize { expected: usize, found: (u32, u32) },
+    OutOfRange { x: usize, y: usize, height: usize, limits: MapHeight },
+}
+impl fmt::Display for HeightmapImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeightmapImageError::Image(error) => write!(f, "{}", error),
+            HeightmapImageError::WrongSize { expected, found } => write!(
+                f,
+                "the image is {}x{}, but the layer size is {}x{}",
+                found.0, found.1, expected, expected
+            ),
+            HeightmapImageError::OutOfRange { x, y, height, limits } => write!(
+                f,
+                "the tile at ({}, {}) has height {}, outside of the map heights {} to {}",
+                x, y, height, limits.min.value, limits.max.value - 1
+            ),
+        }
+    }
+}
+impl std::error::Error for HeightmapImageError {}
+impl From<ImageError> for HeightmapImageError {
+    fn from(error: ImageError) -> Self {
+        HeightmapImageError::Image(error)
+    }
+}
+
+// Image rows go top to bottom while tile y goes up the screen, so rows are flipped to match what the game shows.
+fn image_row(y: usize, length: usize) -> u32 {
+    (length - 1 - y) as u32
+}
+
+pub fn export_heightmap(heightmap: &HeightMap, path: &Path) -> Result<(), ImageError> {
+    let (width, length) = heightmap.dim();
+    let mut image: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::new(width as u32, length as u32);
+    for ((x, y), height) in heightmap.indexed_iter() {
+        let value = (height.value as u16).saturating_mul(HEIGHT_STEP);
+        image.put_pixel(x as u32, image_row(y, length), Luma([value]));
+    }
+    image.save(path)
+}
+
+pub fn import_heightmap(path: &Path, layer_size: usize, height_limits: &MapHeight) -> Result<HeightMap, HeightmapImageError> {
+    let image = image::open(path)?.to_luma16();
+    if image.width() as usize != layer_size || image.height() as usize != layer_size {
+        return Err(HeightmapImageError::WrongSize { expected: layer_size, found: image.dimensions() });
+    }
+    let mut heightmap = HeightMap::from_elem([layer_size, layer_size], height_limits.min);
+    for ((x, y), height) in heightmap.indexed_iter_mut() {
+        let Luma([value]) = *image.get_pixel(x as u32, image_row(y, layer_size));
+        let value = ((value as f64) / HEIGHT_STEP as f64).round() as usize;
+        if value < height_limits.min.value || value >= height_limits.max.value {
+            return Err(HeightmapImageError::OutOfRange { x, y, height: value, limits: *height_limits });
+        }
+        *height = Height { value };
+    }
+    Ok(heightmap)
+}
+
+// A top down picture of the world, coloured by the material on top of each column and shaded by its height.
+pub fn export_preview(world: &World3D, registry: &MaterialRegistry, height_limits: &MapHeight, path: &Path) -> Result<(), ImageError> {
+    let size = world.size();
+    let interval: f32 = height_limits.height_interval().into();
+    let mut image = RgbImage::new(size.x as u32, size.y as u32);
+    for x in 0..size.x {
+        for y in 0..size.y {
+            let top = world.column_top((x, y));
+            let color = world.get(GameTilePos { x, y, z: top.value }).map_or([0, 0, 0], |id| registry.get(id).map_color);
+            let shade = 0.6 + 0.4 * ((top - height_limits.min).value as f32 / (interval - 1.0).max(1.0));
+            image.put_pixel(x as u32, image_row(y, size.y), Rgb(color.map(|channel| (channel as f32 * shade) as u8)));
+        }
+    }
+    image.save(path)
+}
+
+fn export_path(name: &str) -> PathBuf {
+    Path::new(EXPORT_DIRECTORY).join(name)
+}
+
+pub fn export_menu(mut egui_context: ResMut<EguiContext>, mut status: Local<String>, map_settings: Res<MapSettings>, world: Res<World3D>, registry: Res<MaterialRegistry>) {
+    egui::Window::new("Export").show(egui_context.ctx_mut(), |ui| {
+        let heightmap_clicked = ui.button("Export heightmap").clicked();
+        let preview_clicked = ui.button("Export preview").clicked();
+        if heightmap_clicked || preview_clicked {
+            let path = export_path(if heightmap_clicked { "heightmap.png" } else { "preview.png" });
+            let result = fs::create_dir_all(EXPORT_DIRECTORY).map_err(ImageError::IoError).and_then(|_| {
+                if heightmap_clicked {
+                    export_heightmap(&map_settings.heightmap, &path)
+                } else {
+                    export_preview(&world, &registry, &map_settings.height_limits, &path)
+                }
+            });
+            *status = match result {
+                Ok(()) => format!("Exported to {}", path.display()),
+                Err(error) => format!("Export failed: {}", error),
+            };
+        }
+        if !status.is_empty() {
+            ui.label(status.as_str());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GrayImage;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rusted-fortress-{}-{}.png", name, std::process::id()))
+    }
+
+    fn import_gray(name: &str, image: GrayImage, layer_size: usize, height_limits: &MapHeight) -> Result<HeightMap, HeightmapImageError> {
+        let path = temp_path(name);
+        image.save(&path).unwrap();
+        let result = import_heightmap(&path, layer_size, height_limits);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn gray_levels_are_heights() {
+        // Gray level x + 10 * row, with the bottom row of the image being y = 0.
+        let image = GrayImage::from_fn(4, 4, |x, row| Luma([(x + 10 * (3 - row)) as u8]));
+        let heightmap = import_gray("gray-levels", image, 4, &MapHeight::new(64, 0)).unwrap();
+        for ((x, y), height) in heightmap.indexed_iter() {
+            assert_eq!(height.value, x + 10 * y, "at ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn exported_heightmaps_import_unchanged() {
+        let height_limits = MapHeight::new(256, 0);
+        let heightmap = HeightMap::from_shape_fn([8, 8], |(x, y)| Height { value: x * 31 + y * 4 });
+        let path = temp_path("round-trip");
+        export_heightmap(&heightmap, &path).unwrap();
+        let imported = import_heightmap(&path, 8, &height_limits);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(imported.unwrap(), heightmap);
+    }
+
+    #[test]
+    fn rejects_images_of_the_wrong_size() {
+        let result = import_gray("wrong-size", GrayImage::new(16, 8), 16, &MapHeight::new(64, 0));
+        assert!(matches!(result, Err(HeightmapImageError::WrongSize { expected: 16, found: (16, 8) })));
+    }
+
+    #[test]
+    fn rejects_heights_outside_the_map() {
+        let mut image = GrayImage::from_pixel(4, 4, Luma([20]));
+        image.put_pixel(2, 0, Luma([64]));
+        let result = import_gray("out-of-range", image, 4, &MapHeight::new(64, 10));
+        assert!(matches!(result, Err(HeightmapImageError::OutOfRange { x: 2, y: 3, height: 64, .. })));
+        let low = import_gray("too-low", GrayImage::from_pixel(4, 4, Luma([9])), 4, &MapHeight::new(64, 10));
+        assert!(matches!(low, Err(HeightmapImageError::OutOfRange { height: 9, .. })));
+    }
+}
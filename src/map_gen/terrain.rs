@@ -0,0 +1,112 @@
+use ndarray::Array2;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin, RidgedMulti};
+use strum::{Display, EnumIter};
+use serde::{Deserialize, Serialize};
+
+use crate::tiles::GameTilemapSize;
+
+use super::{stage_seed, GenerationStage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, Display, Serialize, Deserialize)]
+pub enum FractalKind {
+    Fbm,
+    Ridged,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TerrainNoise {
+    pub fractal: FractalKind,
+    pub octaves: usize,
+    pub lacunarity: f64,
+    pub persistence: f64,
+    // How far, in tiles, the domain warp can push a sample away from its tile.
+    pub warp_strength: f64,
+    pub warp_scaling: f64,
+    pub continent_scaling: f64,
+    // 0 ignores the continent mask, 1 lets it fully decide where land rises.
+    pub continent_strength: f64,
+}
+impl Default for TerrainNoise {
+    fn default() -> Self {
+        Self {
+            fractal: FractalKind::Fbm,
+            octaves: 5,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            warp_strength: 4.0,
+            warp_scaling: 0.05,
+            continent_scaling: 0.015,
+            continent_strength: 0.5,
+        }
+    }
+}
+
+enum Fractal {
+    Fbm(Fbm<Perlin>),
+    Ridged(RidgedMulti<Perlin>),
+}
+impl Fractal {
+    fn new(seed: u32, settings: &TerrainNoise) -> Self {
+        match settings.fractal {
+            FractalKind::Fbm => Self::Fbm(
+                Fbm::<Perlin>::new(seed)
+                    .set_octaves(settings.octaves)
+                    .set_lacunarity(settings.lacunarity)
+                    .set_persistence(settings.persistence),
+            ),
+            FractalKind::Ridged => Self::Ridged(
+                RidgedMulti::<Perlin>::new(seed)
+                    .set_octaves(settings.octaves)
+                    .set_lacunarity(settings.lacunarity)
+                    .set_persistence(settings.persistence),
+            ),
+        }
+    }
+    fn get(self: &Self, point: [f64; 2]) -> f64 {
+        match self {
+            Self::Fbm(noise) => noise.get(point),
+            Self::Ridged(noise) => noise.get(point),
+        }
+    }
+}
+
+fn to_unit(value: f64) -> f64 {
+    ((value + 1.0) / 2.0).clamp(0.0, 1.0)
+}
+
+fn smoothstep(value: f64) -> f64 {
+    value * value * (3.0 - 2.0 * value)
+}
+
+// Samples the terrain noise pipeline for every column, returning elevations in 0..=1. Fractal noise rarely gets
+// near its theoretical extremes, so the result is stretched until the lowest column sits at 0 and the highest at 1.
+pub fn sample_terrain(seed: u32, tilemap_size: &GameTilemapSize, map_scaling: f64, settings: &TerrainNoise) -> Array2<f64> {
+    let detail = Fractal::new(stage_seed(seed, GenerationStage::Heightmap), settings);
+    let warp_seed = stage_seed(seed, GenerationStage::DomainWarp);
+    let warp_x = Perlin::new(warp_seed);
+    let warp_y = Perlin::new(warp_seed.wrapping_add(1));
+    let continents = Perlin::new(stage_seed(seed, GenerationStage::Continents));
+
+    let shape: [usize; 2] = (*tilemap_size).into();
+    let mut elevation = Array2::zeros(shape);
+    for (x, y) in tilemap_size.into_iter() {
+        let (x_f, y_f) = (x as f64, y as f64);
+
+        let warp_point = [x_f * settings.warp_scaling, y_f * settings.warp_scaling];
+        let warped_x = x_f + warp_x.get(warp_point) * settings.warp_strength;
+        let warped_y = y_f + warp_y.get(warp_point) * settings.warp_strength;
+        let detail_value = to_unit(detail.get([warped_x * map_scaling, warped_y * map_scaling]));
+
+        let continent_value = smoothstep(to_unit(continents.get([x_f * settings.continent_scaling, y_f * settings.continent_scaling])));
+        let shaped = continent_value * 0.65 + detail_value * 0.35;
+        let strength = settings.continent_strength.clamp(0.0, 1.0);
+
+        elevation[[x, y]] = (detail_value * (1.0 - strength) + shaped * strength).clamp(0.0, 1.0);
+    }
+    let lowest = elevation.iter().copied().fold(f64::INFINITY, f64::min);
+    let highest = elevation.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if highest - lowest > f64::EPSILON {
+        elevation.mapv_inplace(|value| (value - lowest) / (highest - lowest));
+    }
+    elevation
+}
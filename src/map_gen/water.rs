@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::texture_loader::TileType::*;
+
+use super::height::Height;
+use super::{stage_seed, GenerationStage, HeightMap, MapHeight, VoxelMap};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WaterSettings {
+    pub river_count: usize,
+    // Rivers only spring from columns at least this far up the height range, as a fraction.
+    pub river_source_height: f64,
+    pub river_depth: usize,
+}
+impl Default for WaterSettings {
+    fn default() -> Self {
+        Self {
+            river_count: 6,
+            river_source_height: 0.6,
+            river_depth: 1,
+        }
+    }
+}
+
+pub type River = Vec<(usize, usize)>;
+
+const NEIGHBOURS: [(isize, isize); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+fn neighbours(position: (usize, usize), dim: (usize, usize)) -> impl Iterator<Item = (usize, usize)> {
+    NEIGHBOURS.iter().filter_map(move |(dx, dy)| {
+        let x = position.0 as isize + dx;
+        let y = position.1 as isize + dy;
+        if x < 0 || y < 0 || x as usize >= dim.0 || y as usize >= dim.1 {
+            return None;
+        }
+        Some((x as usize, y as usize))
+    })
+}
+
+// Walks each river downhill from a high source until it reaches the sea, another river or the map edge,
+// lowering the heightmap along the way so every river runs in a bed that never flows uphill.
+pub fn trace_rivers(seed: u32, heightmap: &mut HeightMap, height_limits: &MapHeight, sea_level: Height, settings: &WaterSettings) -> Vec<River> {
+    let mut rng = StdRng::seed_from_u64(stage_seed(seed, GenerationStage::Rivers) as u64);
+    let dim = heightmap.dim();
+    let interval: f64 = height_limits.height_interval().into();
+    let source_height = height_limits.min + (interval * settings.river_source_height) as usize;
+    let bed_floor = height_limits.min + 1;
+
+    let sources: Vec<(usize, usize)> = heightmap.indexed_iter()
+        .filter(|(_, height)| **height >= source_height && **height > sea_level)
+        .map(|(position, _)| position)
+        .collect();
+    if sources.is_empty() {
+        return Vec::new();
+    }
+
+    let mut river_tiles: HashSet<(usize, usize)> = HashSet::new();
+    let mut rivers = Vec::new();
+    for _ in 0..settings.river_count {
+        let mut position = sources[rng.gen_range(0..sources.len())];
+        if river_tiles.contains(&position) {
+            continue;
+        }
+        let mut river = vec![position];
+        let mut bed = heightmap[position].max(bed_floor + settings.river_depth) - settings.river_depth;
+        heightmap[position] = bed;
+
+        // A river can never be longer than the number of columns, so this always terminates.
+        for _ in 0..dim.0 * dim.1 {
+            if bed <= sea_level || position.0 == 0 || position.1 == 0 || position.0 == dim.0 - 1 || position.1 == dim.1 - 1 {
+                break;
+            }
+            // Follow the steepest descent; in a pit, cut through the lowest rim instead.
+            let next = neighbours(position, dim)
+                .filter(|neighbour| !river.contains(neighbour))
+                .min_by_key(|neighbour| heightmap[*neighbour]);
+            let next = match next {
+                Some(next) => next,
+                None => break,
+            };
+            let joined = river_tiles.contains(&next);
+            bed = (heightmap[next].max(bed_floor + settings.river_depth) - settings.river_depth).min(bed);
+            if !joined {
+                heightmap[next] = bed;
+            }
+            position = next;
+            river.push(position);
+            if joined {
+                break;
+            }
+        }
+
+        river_tiles.extend(river.iter().copied());
+        rivers.push(river);
+    }
+    rivers
+}
+
+// Floods every column below sea level, fills the river beds and turns the river banks to sand.
+pub fn fill_water(voxels: &mut VoxelMap, heightmap: &HeightMap, height_limits: &MapHeight, sea_level: Height, rivers: &[River], settings: &WaterSettings) {
+    let min: usize = height_limits.min.into();
+    let max: usize = height_limits.max.into();
+
+    for ((x, y), surface) in heightmap.indexed_iter() {
+        let surface: usize = (*surface).into();
+        let sea_level: usize = sea_level.into();
+        for z in (surface + 1)..=sea_level.min(max - 1) {
+            voxels[[x, y, z - min]] = Some(Water);
+        }
+    }
+
+    for river in rivers {
+        for &(x, y) in river {
+            let bed: usize = heightmap[[x, y]].into();
+            voxels[[x, y, bed - min]] = Some(Sand);
+            for z in (bed + 1)..=(bed + settings.river_depth).min(max - 1) {
+                voxels[[x, y, z - min]] = Some(Water);
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::{Index, IndexMut};
+use std::path::Path;
+
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+
+use crate::texture_loader::TileType;
+
+pub const ASSET_DIRECTORY: &str = "assets";
+pub const MATERIALS_PATH: &str = "materials/core.materials.ron";
+
+// Index of a material in the MaterialRegistry. The built in TileTypes always keep their own position,
+// so world generation can keep naming them directly, materials only defined in files come after them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct MaterialId(pub u16);
+impl From<TileType> for MaterialId {
+    fn from(tile_type: TileType) -> Self {
+        MaterialId(tile_type as u16)
+    }
+}
+
+impl<T> Index<MaterialId> for Vec<T>{
+    type Output = T;
+
+    fn index(&self, index: MaterialId) -> &Self::Output {
+        &self[index.0 as usize]
+    }
+}
+impl<T> IndexMut<MaterialId> for Vec<T>{
+    fn index_mut(&mut self, index: MaterialId) -> &mut Self::Output {
+        &mut self[index.0 as usize]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MaterialDefinition {
+    pub name: String,
+    // Path of the texture, relative to the assets folder.
+    pub texture: String,
+    // More textures drawn in place of the main one, picked per tile from its position so the ground does not repeat.
+    #[serde(default)]
+    pub variants: Vec<String>,
+    // Mirrors and rotates the texture per tile as well, for textures that look right any way around.
+    #[serde(default)]
+    pub flip: bool,
+    // A material with a blend priority bleeds a soft fringe of its map colour onto neighbouring tiles
+    // with a lower priority, or with none.
+    #[serde(default)]
+    pub blend_priority: Option<u8>,
+    #[serde(default = "default_hardness")]
+    pub hardness: f32,
+    // Multiplied onto the texture when drawing the tile.
+    #[serde(default = "default_tint")]
+    pub tint: [f32; 3],
+    // Colour of the material in exported map previews.
+    #[serde(default = "default_map_color")]
+    pub map_color: [u8; 3],
+    // Name of what mining the material gives, if anything.
+    #[serde(default)]
+    pub yields: Option<String>,
+    #[serde(default)]
+    pub transparent: bool,
+    #[serde(default)]
+    pub liquid: bool,
+}
+impl MaterialDefinition {
+    // What a built in material looks like when no definition file mentions it.
+    pub fn builtin(tile_type: TileType) -> Self {
+        Self {
+            name: tile_type.to_string(),
+            texture: format!("tiles/{}.png", tile_type),
+            variants: Vec::new(),
+            flip: false,
+            blend_priority: None,
+            hardness: default_hardness(),
+            tint: default_tint(),
+            map_color: default_map_color(),
+            yields: None,
+            transparent: false,
+            liquid: false,
+        }
+    }
+    // The main texture followed by the variants.
+    pub fn textures(self: &Self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.texture.as_str()).chain(self.variants.iter().map(String::as_str))
+    }
+    pub fn map_color(self: &Self) -> Color {
+        Color::rgb_u8(self.map_color[0], self.map_color[1], self.map_color[2])
+    }
+    // Tints a colour, leaving its alpha alone.
+    pub fn apply_tint(self: &Self, color: Color) -> Color {
+        color * Vec3::from(self.tint)
+    }
+}
+
+fn default_hardness() -> f32 {
+    1.0
+}
+fn default_tint() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+fn default_map_color() -> [u8; 3] {
+    [128, 128, 128]
+}
+
+// The contents of a *.materials.ron file.
+#[derive(Deserialize, TypeUuid)]
+#[uuid = "5b1c7e0a-3f3e-4a8e-9d61-2c4f8a9e7b13"]
+pub struct MaterialDefinitions {
+    pub materials: Vec<MaterialDefinition>,
+}
+
+#[derive(Default)]
+pub struct MaterialDefinitionsLoader;
+
+impl AssetLoader for MaterialDefinitionsLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let definitions: MaterialDefinitions = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(definitions));
+            Ok(())
+        })
+    }
+    fn extensions(&self) -> &[&str] {
+        &["materials.ron"]
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct MaterialRegistry {
+    definitions: Vec<MaterialDefinition>,
+    by_name: HashMap<String, MaterialId>,
+}
+impl MaterialRegistry {
+    // Built in materials take their TileType's position whether or not the definitions mention them,
+    // and a later definition with the same name replaces an earlier one.
+    pub fn new(definitions: &[MaterialDefinition]) -> Self {
+        let mut registry = Self {
+            definitions: TileType::iter().map(MaterialDefinition::builtin).collect(),
+            by_name: HashMap::new(),
+        };
+        for (index, definition) in registry.definitions.iter().enumerate() {
+            registry.by_name.insert(definition.name.clone(), MaterialId(index as u16));
+        }
+        for definition in definitions {
+            match registry.by_name.get(&definition.name) {
+                Some(id) => registry.definitions[id.0 as usize] = definition.clone(),
+                None => {
+                    registry.by_name.insert(definition.name.clone(), MaterialId(registry.definitions.len() as u16));
+                    registry.definitions.push(definition.clone());
+                }
+            }
+        }
+        registry
+    }
+    pub fn builtin() -> Self {
+        Self::new(&[])
+    }
+    // For running without the asset server, such as from the command line.
+    pub fn load_file(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|error| error.to_string())?;
+        let definitions: MaterialDefinitions = ron::from_str(&contents).map_err(|error| error.to_string())?;
+        Ok(Self::new(&definitions.materials))
+    }
+    // The registry the windowed game would end up with, read straight from the assets folder.
+    pub fn load_default() -> Self {
+        let path = Path::new(ASSET_DIRECTORY).join(MATERIALS_PATH);
+        match Self::load_file(&path) {
+            Ok(registry) => registry,
+            Err(error) => {
+                eprintln!("Using the built in materials, could not read {}: {}", path.display(), error);
+                Self::builtin()
+            }
+        }
+    }
+    pub fn get(self: &Self, id: MaterialId) -> &MaterialDefinition {
+        &self.definitions[id]
+    }
+    pub fn name(self: &Self, id: MaterialId) -> &str {
+        &self.get(id).name
+    }
+    pub fn id(self: &Self, name: &str) -> Option<MaterialId> {
+        self.by_name.get(name).copied()
+    }
+    pub fn len(self: &Self) -> usize {
+        self.definitions.len()
+    }
+    pub fn iter(self: &Self) -> impl Iterator<Item = (MaterialId, &MaterialDefinition)> {
+        self.definitions.iter().enumerate().map(|(index, definition)| (MaterialId(index as u16), definition))
+    }
+    // Where every material of an older registry ended up in this one, indexed by the old id. Materials are matched
+    // by name, so fails with the name of the first one this registry no longer has.
+    pub fn remap_from(self: &Self, old: &MaterialRegistry) -> Result<Vec<MaterialId>, String> {
+        old.iter().map(|(_, definition)| self.id(&definition.name).ok_or_else(|| definition.name.clone())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_definitions_load_with_their_textures() {
+        let registry = MaterialRegistry::load_file(&Path::new(ASSET_DIRECTORY).join(MATERIALS_PATH)).unwrap();
+        assert_eq!(registry.len(), TileType::iter().count());
+        assert!(registry.iter().any(|(_, definition)| !definition.variants.is_empty()));
+        for (_, definition) in registry.iter() {
+            for texture in definition.textures() {
+                assert!(Path::new(ASSET_DIRECTORY).join(texture).is_file(), "{} is missing {}", definition.name, texture);
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_egui::EguiContext;
+
+use crate::camera::DisplayHeight;
+use crate::chunk_render::{lowest_visible_layer, map_origin, ViewDepth};
+use crate::map_gen::MapSettings;
+use crate::tiles::GameTilePos;
+use crate::world3d::World3D;
+use crate::GameState::Game;
+
+const HIGHLIGHT_COLOR: Color = Color::rgba(1.0, 1.0, 0.4, 0.35);
+
+// The tile under the mouse cursor: the first solid tile found looking down from the displayed layer through
+// the visible layers below it. None while the cursor is off the map, over open air or over the UI.
+#[derive(Resource, Default, Clone, Copy)]
+pub struct HoveredTile {
+    pub position: Option<GameTilePos>,
+}
+
+#[derive(Component)]
+pub struct HoverHighlight;
+
+pub struct PickingPlugin;
+
+impl Plugin for PickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HoveredTile>()
+        .add_system_set(
+            SystemSet::on_enter(Game)
+                .with_system(spawn_highlight)
+        )
+        .add_system_set(
+            SystemSet::on_update(Game)
+                .with_system(pick_tile)
+                .with_system(move_highlight.after(pick_tile))
+        );
+    }
+}
+
+// World position of a point on the window, with the window's bottom left corner as the origin.
+pub fn cursor_to_world(cursor: Vec2, window: &Window, camera: &Transform, projection: &OrthographicProjection) -> Vec2 {
+    let window_center = Vec2::new(window.width(), window.height()) / 2.0;
+    camera.translation.truncate() + (cursor - window_center) * projection.scale
+}
+
+// The column of the map a world position falls in, if it falls on the map at all.
+pub fn world_to_column(world_position: Vec2, map_settings: &MapSettings) -> Option<(usize, usize)> {
+    let tile_size = Vec2::new(map_settings.tile_size.x, map_settings.tile_size.y);
+    let tile = ((world_position - map_origin(map_settings).truncate()) / tile_size + 0.5).floor();
+    let layer_size = map_settings.layer_size.size;
+    if tile.x < 0.0 || tile.y < 0.0 || tile.x >= layer_size.x as f32 || tile.y >= layer_size.y as f32 {
+        return None;
+    }
+    Some((tile.x as usize, tile.y as usize))
+}
+
+// Looks down the column from the displayed layer, the same way the renderer stacks layers.
+pub fn first_visible_tile(column: (usize, usize), display_height: &DisplayHeight, view_depth: &ViewDepth, map_settings: &MapSettings, world: &World3D) -> Option<GameTilePos> {
+    let bottom = lowest_visible_layer(display_height, view_depth, map_settings);
+    (bottom..=display_height.height.value).rev()
+        .map(|z| GameTilePos { x: column.0, y: column.1, z })
+        .find(|position| world.is_solid(*position))
+}
+
+// The primary window's cursor and the camera it looks through.
+#[derive(SystemParam)]
+struct CursorView<'w, 's> {
+    windows: Res<'w, Windows>,
+    cameras: Query<'w, 's, (&'static Transform, &'static OrthographicProjection), With<Camera>>,
+}
+impl<'w, 's> CursorView<'w, 's> {
+    fn world_position(self: &Self) -> Option<Vec2> {
+        match (self.windows.get_primary(), self.cameras.get_single()) {
+            (Some(window), Ok((camera, projection))) => window.cursor_position()
+                .map(|cursor| cursor_to_world(cursor, window, camera, projection)),
+            _ => None,
+        }
+    }
+}
+
+fn pick_tile(
+    mut egui_context: ResMut<EguiContext>,
+    cursor: CursorView,
+    display_height: Res<DisplayHeight>,
+    view_depth: Res<ViewDepth>,
+    map_settings: Res<MapSettings>,
+    world: Res<World3D>,
+    mut hovered: ResMut<HoveredTile>)
+    {
+    let position = if egui_context.ctx_mut().is_pointer_over_area() {
+        None
+    } else {
+        cursor.world_position()
+            .and_then(|world_position| world_to_column(world_position, &map_settings))
+            .and_then(|column| first_visible_tile(column, &display_height, &view_depth, &map_settings, &world))
+    };
+    if hovered.position != position {
+        hovered.position = position;
+    }
+}
+
+fn spawn_highlight(mut commands: Commands, map_settings: Res<MapSettings>) {
+    commands.spawn((
+        SpriteBundle {
+            sprite: Sprite {
+                color: HIGHLIGHT_COLOR,
+                custom_size: Some(Vec2::new(map_settings.tile_size.x, map_settings.tile_size.y)),
+                ..Default::default()
+            },
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        },
+        HoverHighlight
+    ));
+}
+
+fn move_highlight(
+    hovered: Res<HoveredTile>,
+    map_settings: Res<MapSettings>,
+    mut highlights: Query<(&mut Transform, &mut Visibility), With<HoverHighlight>>)
+    {
+    if !hovered.is_changed() {
+        return;
+    }
+    for (mut transform, mut visibility) in highlights.iter_mut() {
+        visibility.is_visible = hovered.position.is_some();
+        if let Some(position) = hovered.position {
+            let offset = Vec3::new(
+                position.x as f32 * map_settings.tile_size.x,
+                position.y as f32 * map_settings.tile_size.y,
+                // Just in front of the hovered tile's layer.
+                position.z as f32 + 0.5,
+            );
+            transform.translation = map_origin(&map_settings) + offset;
+        }
+    }
+}
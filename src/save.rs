@@ -0,0 +1,504 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::DisplayHeight;
+use crate::map_gen::biomes::{Biome, Climate};
+use crate::map_gen::height::Height;
+use crate::map_gen::{HeightMap, MapSettings, Voxel, WorldGenSettings};
+use crate::materials::MaterialRegistry;
+use crate::tiles::{Game3DSize, GameTilePos};
+use crate::world3d::{World3D, CHUNK_VOLUME};
+use crate::tick::{GameClock, GameTick, SPEED_STEPS};
+use crate::GameState::Game;
+
+// Bump this whenever SaveGame changes shape, and teach decode how to read the previous version.
+pub const SAVE_VERSION: u32 = 2;
+const SAVE_MAGIC: &[u8; 4] = b"HIVE";
+pub const SAVE_DIRECTORY: &str = "saves";
+pub const SAVE_EXTENSION: &str = "hive";
+
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_enter(Game)
+                .with_system(restore_loaded_game)
+        );
+    }
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    NotASave,
+    UnsupportedVersion { found: u32, supported: u32 },
+    Corrupt(String),
+    UnknownMaterial(String),
+}
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io(error) => write!(f, "could not access the save file: {}", error),
+            SaveError::NotASave => write!(f, "this file is not a saved game"),
+            SaveError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "the save was made with save format version {}, but this build only reads up to version {}",
+                found, supported
+            ),
+            SaveError::Corrupt(reason) => write!(f, "the save is damaged: {}", reason),
+            SaveError::UnknownMaterial(name) => write!(f, "the save uses an unknown material \"{}\"", name),
+        }
+    }
+}
+impl std::error::Error for SaveError {}
+impl From<io::Error> for SaveError {
+    fn from(error: io::Error) -> Self {
+        SaveError::Io(error)
+    }
+}
+impl From<bincode::Error> for SaveError {
+    fn from(error: bincode::Error) -> Self {
+        match *error {
+            bincode::ErrorKind::Io(error) => SaveError::Io(error),
+            error => SaveError::Corrupt(error.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedMap {
+    pub settings: WorldGenSettings,
+    pub heightmap: HeightMap,
+    pub temperature: Array2<f64>,
+    pub moisture: Array2<f64>,
+    pub biomes: Array2<Biome>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum SavedChunk {
+    Uniform(u16),
+    Voxels(Vec<u16>),
+}
+
+// Materials are stored by name rather than by their id in the MaterialRegistry, so adding or reordering material
+// definitions does not scramble older saves.
+#[derive(Serialize, Deserialize)]
+pub struct SavedWorld {
+    size: Game3DSize,
+    min_height: Height,
+    palette: Vec<Option<String>>,
+    chunks: Vec<SavedChunk>,
+}
+impl SavedWorld {
+    fn capture(world: &World3D, registry: &MaterialRegistry) -> Self {
+        let mut palette: Vec<Voxel> = Vec::new();
+        let mut palette_index = |voxel: Voxel| match palette.iter().position(|entry| *entry == voxel) {
+            Some(index) => index as u16,
+            None => {
+                palette.push(voxel);
+                (palette.len() - 1) as u16
+            }
+        };
+        let chunks = world.chunk_positions().map(|chunk_pos| match world.uniform_voxel(chunk_pos) {
+            Some(voxel) => SavedChunk::Uniform(palette_index(voxel)),
+            None => SavedChunk::Voxels(world.chunk_voxels(chunk_pos).into_iter().map(&mut palette_index).collect()),
+        }).collect();
+        Self {
+            size: world.size(),
+            min_height: world.min_height(),
+            palette: palette.into_iter().map(|voxel| voxel.map(|id| registry.name(id).to_string())).collect(),
+            chunks,
+        }
+    }
+    fn restore(self: Self, registry: &MaterialRegistry) -> Result<World3D, SaveError> {
+        let palette = self.palette.iter().map(|name| match name {
+            Some(name) => registry.id(name).map(Some).ok_or_else(|| SaveError::UnknownMaterial(name.clone())),
+            None => Ok(None),
+        }).collect::<Result<Vec<Voxel>, SaveError>>()?;
+        let lookup = |index: u16| palette.get(index as usize).copied().ok_or_else(|| SaveError::Corrupt(format!("material {} is not in the palette", index)));
+
+        let mut world = World3D::new(self.size, self.min_height, None);
+        let chunk_positions: Vec<_> = world.chunk_positions().collect();
+        if chunk_positions.len() != self.chunks.len() {
+            return Err(SaveError::Corrupt(format!("expected {} chunks, found {}", chunk_positions.len(), self.chunks.len())));
+        }
+        for (chunk_pos, chunk) in chunk_positions.into_iter().zip(self.chunks) {
+            let voxels = match chunk {
+                SavedChunk::Uniform(index) => vec![lookup(index)?; CHUNK_VOLUME],
+                SavedChunk::Voxels(indices) => indices.into_iter().map(lookup).collect::<Result<Vec<Voxel>, SaveError>>()?,
+            };
+            if voxels.len() != CHUNK_VOLUME {
+                return Err(SaveError::Corrupt(String::from("a chunk has the wrong number of voxels")));
+            }
+            world.set_chunk_voxels(chunk_pos, voxels);
+        }
+        world.take_dirty_chunks();
+        Ok(world)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct SavedView {
+    pub display_height: Height,
+    pub camera_translation: [f32; 3],
+    pub camera_scale: f32,
+}
+
+impl SavedView {
+    // Where a fresh game starts, looking at the bottom layer from the middle of the map.
+    pub fn starting(map_settings: &MapSettings) -> Self {
+        let camera = Camera2dBundle::default();
+        Self {
+            display_height: map_settings.height_limits.min,
+            camera_translation: camera.transform.translation.to_array(),
+            camera_scale: camera.projection.scale,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SavedEntity {
+    pub position: GameTilePos,
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct SavedClock {
+    pub tick: u64,
+    pub speed: usize,
+    pub paused: bool,
+}
+impl SavedClock {
+    pub fn capture(clock: &GameClock, tick: &GameTick) -> Self {
+        Self {
+            tick: tick.0,
+            speed: clock.speed,
+            paused: clock.paused,
+        }
+    }
+}
+impl Default for SavedClock {
+    fn default() -> Self {
+        Self::capture(&GameClock::default(), &GameTick::default())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SaveGame {
+    pub map: SavedMap,
+    pub world: SavedWorld,
+    pub view: SavedView,
+    pub clock: SavedClock,
+    pub entities: Vec<SavedEntity>,
+}
+
+// Version 1 saves stored the number of frames per game tick instead of the clock.
+#[derive(Deserialize)]
+struct SaveGameV1 {
+    map: SavedMap,
+    world: SavedWorld,
+    view: SavedView,
+    game_speed: usize,
+    entities: Vec<SavedEntity>,
+}
+impl From<SaveGameV1> for SaveGame {
+    fn from(save: SaveGameV1) -> Self {
+        Self {
+            map: save.map,
+            world: save.world,
+            view: save.view,
+            clock: SavedClock { speed: speed_from_frames_per_tick(save.game_speed), ..SavedClock::default() },
+            entities: save.entities,
+        }
+    }
+}
+
+// Version 1 ticked every that many frames, with 20 as normal speed. Picks the speed step nearest to it.
+fn speed_from_frames_per_tick(frames: usize) -> usize {
+    let multiplier = 20.0 / frames.max(1) as f64;
+    let distance = |step: f64| (step.log2() - multiplier.log2()).abs();
+    (0..SPEED_STEPS.len())
+        .min_by(|a, b| distance(SPEED_STEPS[*a]).total_cmp(&distance(SPEED_STEPS[*b])))
+        .unwrap_or(0)
+}
+impl SaveGame {
+    pub fn capture(map_settings: &MapSettings, world: &World3D, registry: &MaterialRegistry, view: SavedView, clock: SavedClock, entities: Vec<SavedEntity>) -> Self {
+        Self {
+            map: SavedMap {
+                settings: map_settings.world_gen_settings(),
+                heightmap: map_settings.heightmap.clone(),
+                temperature: map_settings.temperature.clone(),
+                moisture: map_settings.moisture.clone(),
+                biomes: map_settings.biomes.clone(),
+            },
+            world: SavedWorld::capture(world, registry),
+            view,
+            clock,
+            entities,
+        }
+    }
+    pub fn write(self: &Self, path: &Path) -> Result<(), SaveError> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(SAVE_MAGIC)?;
+        file.write_all(&SAVE_VERSION.to_le_bytes())?;
+        let mut encoder = DeflateEncoder::new(file, Compression::default());
+        bincode::serialize_into(&mut encoder, self)?;
+        encoder.finish()?.flush()?;
+        Ok(())
+    }
+    pub fn read(path: &Path) -> Result<Self, SaveError> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        let mut version = [0; 4];
+        if file.read_exact(&mut magic).is_err() || &magic != SAVE_MAGIC || file.read_exact(&mut version).is_err() {
+            return Err(SaveError::NotASave);
+        }
+        Self::decode(u32::from_le_bytes(version), DeflateDecoder::new(file))
+    }
+    // Older versions get their own arm here that reads the old layout and upgrades it to the current one.
+    fn decode(version: u32, reader: impl Read) -> Result<Self, SaveError> {
+        match version {
+            1 => Ok(bincode::deserialize_from::<_, SaveGameV1>(reader)?.into()),
+            SAVE_VERSION => Ok(bincode::deserialize_from(reader)?),
+            found => Err(SaveError::UnsupportedVersion { found, supported: SAVE_VERSION }),
+        }
+    }
+}
+
+// A save read from disk, waiting for world generation and the camera to pick it up instead of starting a new world.
+#[derive(Resource)]
+pub struct LoadedGame {
+    pub map: SavedMap,
+    pub world: Option<World3D>,
+    pub view: SavedView,
+    pub clock: SavedClock,
+    pub entities: Vec<SavedEntity>,
+}
+impl LoadedGame {
+    pub fn read(path: &Path, registry: &MaterialRegistry) -> Result<Self, SaveError> {
+        let save = SaveGame::read(path)?;
+        Ok(Self {
+            map: save.map,
+            world: Some(save.world.restore(registry)?),
+            view: save.view,
+            clock: save.clock,
+            entities: save.entities,
+        })
+    }
+    pub fn climate(self: &Self) -> Climate {
+        Climate {
+            temperature: self.map.temperature.clone(),
+            moisture: self.map.moisture.clone(),
+            biomes: self.map.biomes.clone(),
+        }
+    }
+}
+
+pub fn save_path(name: &str) -> PathBuf {
+    Path::new(SAVE_DIRECTORY).join(name).with_extension(SAVE_EXTENSION)
+}
+
+// Every save in the save directory, newest name last.
+pub fn list_saves() -> Vec<PathBuf> {
+    let mut saves: Vec<PathBuf> = match fs::read_dir(SAVE_DIRECTORY) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == SAVE_EXTENSION))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    saves.sort();
+    saves
+}
+
+fn restore_loaded_game(mut commands: Commands, loaded: Option<Res<LoadedGame>>, mut clock: ResMut<GameClock>, mut tick: ResMut<GameTick>) {
+    let loaded = match loaded {
+        Some(loaded) => loaded,
+        None => return,
+    };
+    clock.speed = loaded.clock.speed;
+    clock.paused = loaded.clock.paused;
+    tick.0 = loaded.clock.tick;
+    for entity in loaded.entities.iter() {
+        let mut spawned = commands.spawn(entity.position);
+        if let Some(name) = &entity.name {
+            spawned.insert(Name::new(name.clone()));
+        }
+    }
+    commands.remove_resource::<LoadedGame>();
+}
+
+// Everything that goes into a save made from the running game.
+#[derive(SystemParam)]
+pub struct SaveSources<'w, 's> {
+    map_settings: Res<'w, MapSettings>,
+    world: Res<'w, World3D>,
+    registry: Res<'w, MaterialRegistry>,
+    display_height: Res<'w, DisplayHeight>,
+    clock: Res<'w, GameClock>,
+    tick: Res<'w, GameTick>,
+    cameras: Query<'w, 's, (&'static Transform, &'static OrthographicProjection), With<Camera>>,
+    entities: Query<'w, 's, (&'static GameTilePos, Option<&'static Name>)>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+impl<'w, 's> SaveSources<'w, 's> {
+    fn capture(self: &Self) -> SaveGame {
+        let view = match self.cameras.get_single() {
+            Ok((transform, projection)) => SavedView {
+                display_height: self.display_height.height,
+                camera_translation: transform.translation.to_array(),
+                camera_scale: projection.scale,
+            },
+            Err(_) => SavedView::starting(&self.map_settings),
+        };
+        let entities = self.entities.iter().map(|(position, name)| SavedEntity {
+            position: *position,
+            name: name.map(|name| name.as_str().to_string()),
+        }).collect();
+        SaveGame::capture(&self.map_settings, &self.world, &self.registry, view, SavedClock::capture(&self.clock, &self.tick), entities)
+    }
+}
+
+pub fn save_menu(
+    mut egui_context: ResMut<EguiContext>,
+    mut file_name: Local<Option<String>>,
+    mut status: Local<String>,
+    sources: SaveSources)
+    {
+    let file_name = file_name.get_or_insert_with(|| String::from("world"));
+    egui::Window::new("Save").show(egui_context.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Name");
+            ui.text_edit_singleline(file_name);
+        });
+        if ui.button("Save").clicked() {
+            let path = save_path(file_name);
+            *status = match sources.capture().write(&path) {
+                Ok(()) => format!("Saved to {}", path.display()),
+                Err(error) => format!("Saving failed: {}", error),
+            };
+        }
+        if !status.is_empty() {
+            ui.label(status.as_str());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map_gen::fixtures::{generate, voxels};
+    use crate::materials::MaterialId;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rusted-fortress-{}-{}.{}", name, std::process::id(), SAVE_EXTENSION))
+    }
+
+    fn assert_same_world(loaded: &LoadedGame, map_settings: &MapSettings, world: &World3D) {
+        let loaded_world = loaded.world.as_ref().unwrap();
+        assert_eq!(loaded_world.size(), world.size());
+        assert_eq!(loaded_world.min_height(), world.min_height());
+        assert_eq!(voxels(loaded_world), voxels(world));
+        assert_eq!(loaded.map.settings.seed, map_settings.seed);
+        assert_eq!(loaded.map.heightmap, map_settings.heightmap);
+        assert_eq!(loaded.map.biomes, map_settings.biomes);
+    }
+
+    #[test]
+    fn round_trip() {
+        let registry = MaterialRegistry::builtin();
+        let (map_settings, mut world) = generate(99);
+        world.set(GameTilePos { x: 3, y: 4, z: world.min_height().value }, Some(MaterialId(1)));
+        let entities = vec![SavedEntity { position: GameTilePos { x: 1, y: 2, z: 3 }, name: Some(String::from("Urist")) }];
+        let clock = SavedClock { tick: 1234, speed: 2, paused: true };
+        let path = temp_path("round-trip");
+        SaveGame::capture(&map_settings, &world, &registry, SavedView::starting(&map_settings), clock, entities).write(&path).unwrap();
+        let loaded = LoadedGame::read(&path, &registry);
+        fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_same_world(&loaded, &map_settings, &world);
+        assert_eq!((loaded.clock.tick, loaded.clock.speed, loaded.clock.paused), (1234, 2, true));
+        assert_eq!(loaded.entities.len(), 1);
+        let position = loaded.entities[0].position;
+        assert_eq!((position.x, position.y, position.z), (1, 2, 3));
+        assert_eq!(loaded.entities[0].name.as_deref(), Some("Urist"));
+    }
+
+    // Laid out the way version 1 of the format wrote it.
+    #[derive(Serialize)]
+    struct WrittenV1 {
+        map: SavedMap,
+        world: SavedWorld,
+        view: SavedView,
+        game_speed: usize,
+        entities: Vec<SavedEntity>,
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let registry = MaterialRegistry::builtin();
+        let (map_settings, world) = generate(99);
+        let save = SaveGame::capture(&map_settings, &world, &registry, SavedView::starting(&map_settings), SavedClock::default(), Vec::new());
+        // Five frames per tick was four times normal speed.
+        let v1 = WrittenV1 { map: save.map, world: save.world, view: save.view, game_speed: 5, entities: save.entities };
+        let bytes = bincode::serialize(&v1).unwrap();
+
+        let decoded = SaveGame::decode(1, bytes.as_slice()).unwrap();
+        assert_eq!((decoded.clock.tick, decoded.clock.speed, decoded.clock.paused), (0, 2, false));
+        let loaded = LoadedGame {
+            map: decoded.map,
+            world: Some(decoded.world.restore(&registry).unwrap()),
+            view: decoded.view,
+            clock: decoded.clock,
+            entities: decoded.entities,
+        };
+        assert_same_world(&loaded, &map_settings, &world);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = temp_path("not-a-save");
+        fs::write(&path, b"PNG and more").unwrap();
+        let result = SaveGame::read(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(SaveError::NotASave)));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let path = temp_path("future");
+        let mut contents = SAVE_MAGIC.to_vec();
+        contents.extend_from_slice(&(SAVE_VERSION + 1).to_le_bytes());
+        fs::write(&path, contents).unwrap();
+        let result = SaveGame::read(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(SaveError::UnsupportedVersion { found, supported: SAVE_VERSION }) if found == SAVE_VERSION + 1));
+    }
+
+    #[test]
+    fn rejects_unknown_materials() {
+        let registry = MaterialRegistry::builtin();
+        let (map_settings, world) = generate(99);
+        let mut save = SaveGame::capture(&map_settings, &world, &registry, SavedView::starting(&map_settings), SavedClock::default(), Vec::new());
+        save.world.palette.push(Some(String::from("unobtainium")));
+        assert!(matches!(save.world.restore(&registry), Err(SaveError::UnknownMaterial(name)) if name == "unobtainium"));
+    }
+}
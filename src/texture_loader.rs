@@ -1,92 +1,383 @@
-use std::ops::{Index, IndexMut};
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
 
-use bevy::{prelude::{Resource, Handle, Image, Plugin, ResMut, Res, AssetServer, SystemSet, State, Assets, HandleUntyped}, sprite::{TextureAtlas, TextureAtlasBuilder}};
+use bevy::{prelude::{Resource, Handle, Image, Plugin, ResMut, Res, AssetServer, SystemSet, State, Assets, Commands, AddAsset, AssetEvent, EventReader, Local, Query, Vec2}, sprite::{TextureAtlas, TextureAtlasBuilder}, asset::LoadState};
+use bevy::ecs::system::SystemParam;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::log::warn;
 use bevy_ecs_tilemap::tiles::TileTextureIndex;
-use strum::{EnumIter, IntoEnumIterator, Display};
-use crate::{GameState::{InitalizeAssets, self}, next_game_state};
+use bevy_egui::{egui, EguiContext};
+use strum::{EnumIter, EnumString, Display};
+use crate::{GameState::{InitalizeAssets, MainMenu, Game, self}, next_game_state};
+use crate::autotile::{canonical_masks, mask_image};
+use crate::chunk_render::TileMaterial;
+use crate::map_gen::TILE_PIXEL_LENGTH;
+use crate::materials::{MaterialDefinitions, MaterialDefinitionsLoader, MaterialId, MaterialRegistry, MATERIALS_PATH};
+use crate::world3d::World3D;
 
-#[derive(EnumIter, Display)]
+#[derive(EnumIter, EnumString, Display, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[strum(serialize_all = "snake_case")]
 pub enum TileType {
     Shadow = 0,
     Grass,
     Stone,
+    Water,
+    Sand,
+    Soil,
+    Mud,
+    Snow,
+    DryGrass,
+    Sandstone,
+    Limestone,
+    Shale,
+    Marble,
+    Slate,
+    Granite,
+    Basalt,
+    Coal,
+    IronOre,
+    CopperOre,
+    GoldOre,
+    Amethyst,
+    Emerald,
 }
 
-impl<T> Index<TileType> for Vec<T>{
-    type Output = T;
+// Drawn in place of any tile texture that fails to load, loud enough that nobody mistakes it for real art.
+const FALLBACK_CHECKER_SIZE: u32 = 32;
+const FALLBACK_COLORS: [[u8; 4]; 2] = [[255, 0, 255, 255], [0, 0, 0, 255]];
+const ATLAS_MAX_SIZE: f32 = 8192.0;
 
-    fn index(&self, index: TileType) -> &Self::Output {
-        &self[index as usize]
-    }
-}
-impl<T> IndexMut<TileType> for Vec<T>{
-    fn index_mut(&mut self, index: TileType) -> &mut Self::Output {
-        &mut self[index as usize]
-    }
-}
-
-#[derive(Resource)]
+#[derive(Resource, Default)]
 pub struct TileTextureAtlas {
     pub atlas: Option<TextureAtlas>,
-    pub indices: Vec<TileTextureIndex>,
-    vector: Vec<Handle<Image>>
-}
-impl Default for TileTextureAtlas {
-    fn default() -> Self {
-        Self {
-            atlas: None,
-            indices: vec![TileTextureIndex(0); TileType::iter().count()],
-            vector: Vec::new()
+    // Indexed by MaterialId, then by variant.
+    indices: Vec<Vec<TileTextureIndex>>,
+    // The autotile overlays, by canonical mask.
+    masks: HashMap<u8, TileTextureIndex>,
+    vector: Vec<Vec<Handle<Image>>>,
+    mask_images: Vec<(u8, Handle<Image>)>,
+    definitions: Handle<MaterialDefinitions>,
+    fallback: Option<Handle<Image>>,
+}
+impl TileTextureAtlas {
+    // The same seed always picks the same variant, as long as the material keeps its variants.
+    pub fn texture_index(self: &Self, material: MaterialId, seed: u32) -> TileTextureIndex {
+        let variants = &self.indices[material];
+        variants[seed as usize % variants.len()]
+    }
+    pub fn mask_index(self: &Self, mask: u8) -> TileTextureIndex {
+        self.masks.get(&mask).copied().unwrap_or_default()
+    }
+    // Follows the materials to their new ids until the atlas is rebuilt, materials new to the registry get the
+    // first texture in the meantime.
+    fn remap_materials(self: &mut Self, remap: &[MaterialId], materials: usize) {
+        let mut indices = vec![vec![TileTextureIndex(0)]; materials];
+        for (old, variants) in self.indices.drain(..).enumerate() {
+            indices[remap[old].0 as usize] = variants;
         }
+        self.indices = indices;
     }
 }
 
+// How far loading has got, for the loading screen, and every file that could not be loaded.
+#[derive(Resource, Default)]
+pub struct AssetLoadReport {
+    pub loaded: usize,
+    pub total: usize,
+    pub errors: Vec<String>,
+}
+
 pub struct TextureLoaderPlugin;
 
 impl Plugin for TextureLoaderPlugin{
     fn build(&self, app: &mut bevy::prelude::App) {
         app
+        .add_asset::<MaterialDefinitions>()
+        .init_asset_loader::<MaterialDefinitionsLoader>()
         .init_resource::<TileTextureAtlas>()
-        .add_system_set(SystemSet::on_enter(InitalizeAssets).with_system(load_textures))
-        .add_system_set(SystemSet::on_update(InitalizeAssets).with_system(create_texture_atlas));
+        .init_resource::<AssetLoadReport>()
+        .add_system_set(SystemSet::on_enter(InitalizeAssets).with_system(load_material_definitions))
+        .add_system_set(
+            SystemSet::on_update(InitalizeAssets)
+                .with_system(load_textures)
+                .with_system(create_texture_atlas)
+                .with_system(loading_screen)
+        )
+        .add_system_set(SystemSet::on_update(MainMenu).with_system(asset_errors_window))
+        .add_system_set(SystemSet::on_update(Game).with_system(asset_errors_window))
+        .add_system(reload_assets);
     }
 }
 
-fn load_textures(mut texture_handles: ResMut<TileTextureAtlas>, asset_server: Res<AssetServer>){
-    for texture_name in TileType::iter() {
-        let texture_path = format!("tiles/{}.png", texture_name.to_string().to_lowercase());
-        let texture: Handle<Image> = asset_server.load(texture_path);
-        texture_handles.vector.push(texture);
+fn load_material_definitions(mut texture_handles: ResMut<TileTextureAtlas>, asset_server: Res<AssetServer>){
+    texture_handles.definitions = asset_server.load(MATERIALS_PATH);
+}
+
+// Once the definitions are in, builds the registry from them and starts loading every texture they name.
+fn load_textures(
+    mut commands: Commands,
+    mut texture_handles: ResMut<TileTextureAtlas>,
+    mut report: ResMut<AssetLoadReport>,
+    registry: Option<Res<MaterialRegistry>>,
+    definitions: Res<Assets<MaterialDefinitions>>,
+    asset_server: Res<AssetServer>)
+    {
+    if registry.is_some() {
+        return;
     }
+    let registry = match asset_server.get_load_state(&texture_handles.definitions) {
+        LoadState::Loaded => MaterialRegistry::new(&definitions.get(&texture_handles.definitions).unwrap().materials),
+        LoadState::Failed => {
+            report.errors.push(format!("{} could not be loaded, using the built in materials", MATERIALS_PATH));
+            MaterialRegistry::builtin()
+        },
+        _ => return,
+    };
+    texture_handles.vector = load_material_textures(&registry, &asset_server);
+    commands.insert_resource(registry);
+}
+
+fn load_material_textures(registry: &MaterialRegistry, asset_server: &AssetServer) -> Vec<Vec<Handle<Image>>> {
+    registry.iter().map(|(_, definition)| definition.textures().map(|path| asset_server.load(path)).collect()).collect()
 }
 
-fn create_texture_atlas(mut texture_handles: ResMut<TileTextureAtlas>, game_state: ResMut<State<GameState>>, mut textures: ResMut<Assets<Image>>, asset_server: Res<AssetServer>){
-    let mut texture_atlas_builder = TextureAtlasBuilder::default();
-    let untyped_handles: Vec<HandleUntyped> = texture_handles.vector.iter().map(|x| x.clone_untyped()).collect();
-    
-    match asset_server.get_group_load_state(untyped_handles.iter().map(|x| x.id)) {
-        bevy::asset::LoadState::Loaded => {
-            for handle in texture_handles.vector.iter() {
-                let texture = textures.get(handle).unwrap();
-                texture_atlas_builder.add_texture(handle.clone_weak(), texture);
+fn fallback_texture(size: u32) -> Image {
+    let mut data = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let cell = (x / FALLBACK_CHECKER_SIZE + y / FALLBACK_CHECKER_SIZE) % 2;
+            data.extend_from_slice(&FALLBACK_COLORS[cell as usize]);
+        }
+    }
+    Image::new(
+        Extent3d { width: size, height: size, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+// Every texture file the registry uses, once each since materials are free to share one, with the materials using it.
+fn texture_files<'a>(registry: &'a MaterialRegistry, handles: &'a [Vec<Handle<Image>>]) -> Vec<(&'a str, &'a Handle<Image>, Vec<&'a str>)> {
+    let mut files: Vec<(&str, &Handle<Image>, Vec<&str>)> = Vec::new();
+    for ((_, definition), variants) in registry.iter().zip(handles.iter()) {
+        for (texture, handle) in definition.textures().zip(variants.iter()) {
+            match files.iter_mut().find(|(path, _, _)| *path == texture) {
+                Some((_, _, users)) => users.push(definition.name.as_str()),
+                None => files.push((texture, handle, vec![definition.name.as_str()])),
             }
-        
-            let texture_atlas = texture_atlas_builder.finish(&mut textures).unwrap();
-
-            for texture_name in TileType::iter() {
-                let texture_path = format!("tiles/{}.png", texture_name.to_string().to_lowercase());
-                let handle: Handle<Image> = asset_server.get_handle(texture_path);
-                let index = texture_atlas.get_texture_index(&handle).unwrap();
-                texture_handles.indices[texture_name] = TileTextureIndex(index as u32);
+        }
+    }
+    files
+}
+
+// Counts the files that have finished loading, true once none are left loading. Failures are reported then.
+fn textures_settled(files: &[(&str, &Handle<Image>, Vec<&str>)], asset_server: &AssetServer, report: &mut AssetLoadReport) -> bool {
+    let states: Vec<LoadState> = files.iter().map(|(_, handle, _)| asset_server.get_load_state(*handle)).collect();
+    report.total = files.len();
+    report.loaded = states.iter().filter(|state| matches!(state, LoadState::Loaded | LoadState::Failed)).count();
+    if report.loaded < report.total {
+        return false;
+    }
+    for ((path, _, users), state) in files.iter().zip(states) {
+        if state == LoadState::Failed {
+            report.errors.push(format!("{} could not be loaded, used by {}", path, users.join(", ")));
+        }
+    }
+    true
+}
+
+// Builds the atlas with the checker in place of every texture that failed, so a missing file shows up in game
+// rather than stopping it from starting. Each build makes a new atlas image, the old one is dropped with its last handle.
+fn build_atlas(texture_handles: &mut TileTextureAtlas, registry: &MaterialRegistry, report: &mut AssetLoadReport, textures: &mut Assets<Image>) {
+    let fallback = texture_handles.fallback.get_or_insert_with(|| textures.add(fallback_texture(TILE_PIXEL_LENGTH as u32))).clone();
+    if texture_handles.mask_images.is_empty() {
+        texture_handles.mask_images = canonical_masks().into_iter()
+            .map(|mask| (mask, textures.add(mask_image(mask, TILE_PIXEL_LENGTH as u32))))
+            .collect();
+    }
+    // The loaded handles are kept as they are, so fixing a broken file on disk can still replace the checker.
+    // The atlas is cut into a grid of tile sized cells, so a texture of any other size gets the checker as well.
+    let tile_size = Vec2::splat(TILE_PIXEL_LENGTH);
+    let mut wrong_size = HashSet::new();
+    let sources: Vec<Vec<Handle<Image>>> = registry.iter().zip(texture_handles.vector.iter())
+        .map(|((_, definition), variants)| definition.textures().zip(variants.iter()).map(|(path, handle)| match textures.get(handle) {
+            Some(image) if image.size() == tile_size => handle.clone(),
+            Some(image) => {
+                if wrong_size.insert(path) {
+                    report.errors.push(format!(
+                        "{} is {}x{} pixels, tile textures have to be {}x{}",
+                        path, image.size().x, image.size().y, TILE_PIXEL_LENGTH, TILE_PIXEL_LENGTH
+                    ));
+                }
+                fallback.clone()
             }
+            None => fallback.clone(),
+        }).collect())
+        .collect();
 
+    let mut texture_atlas_builder = TextureAtlasBuilder::default().max_size(Vec2::splat(ATLAS_MAX_SIZE));
+    let mut added = HashSet::new();
+    for handle in sources.iter().flatten().chain(texture_handles.mask_images.iter().map(|(_, handle)| handle)) {
+        if added.insert(handle.id()) {
+            texture_atlas_builder.add_texture(handle.clone_weak(), textures.get(handle).unwrap());
+        }
+    }
+    match texture_atlas_builder.finish(textures) {
+        Ok(texture_atlas) => {
+            texture_handles.indices = sources.iter()
+                .map(|variants| variants.iter().map(|handle| grid_index(&texture_atlas, handle)).collect())
+                .collect();
+            texture_handles.masks = texture_handles.mask_images.iter()
+                .map(|(mask, handle)| (*mask, grid_index(&texture_atlas, handle)))
+                .collect();
             texture_handles.atlas = Some(texture_atlas);
-        
-            next_game_state(game_state);
-        },
-        bevy::asset::LoadState::Failed => {
-            println!("Failed to load tile textures")
-        },
+        }
+        Err(error) => {
+            // Tiles still get spawned, they just have nothing to draw.
+            report.errors.push(format!("Could not build the tile atlas: {}", error));
+            texture_handles.indices = vec![vec![TileTextureIndex(0)]; registry.len()];
+            texture_handles.masks.clear();
+        }
+    }
+    for error in report.errors.iter() {
+        warn!("{}", error);
+    }
+}
+
+// The tilemap cuts the atlas into a grid numbered row by row, whatever order the builder packed the textures in.
+fn grid_index(texture_atlas: &TextureAtlas, handle: &Handle<Image>) -> TileTextureIndex {
+    let columns = (texture_atlas.size.x / TILE_PIXEL_LENGTH) as u32;
+    texture_atlas.get_texture_index(handle).map_or(TileTextureIndex(0), |index| {
+        let corner = texture_atlas.textures[index].min / TILE_PIXEL_LENGTH;
+        TileTextureIndex(corner.y as u32 * columns + corner.x as u32)
+    })
+}
+
+// What building the atlas works with, on the loading screen and whenever assets are reloaded.
+#[derive(SystemParam)]
+struct AtlasSources<'w, 's> {
+    texture_handles: ResMut<'w, TileTextureAtlas>,
+    report: ResMut<'w, AssetLoadReport>,
+    textures: ResMut<'w, Assets<Image>>,
+    asset_server: Res<'w, AssetServer>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+impl<'w, 's> AtlasSources<'w, 's> {
+    // Waits for every texture the materials use to load or fail, then builds the atlas.
+    fn build_once_settled(self: &mut Self, registry: &MaterialRegistry) -> bool {
+        let files = texture_files(registry, &self.texture_handles.vector);
+        if !textures_settled(&files, &self.asset_server, &mut self.report) {
+            return false;
+        }
+        build_atlas(&mut self.texture_handles, registry, &mut self.report, &mut self.textures);
+        true
+    }
+}
+
+// What a reload of the material definitions moves onto the new material ids.
+#[derive(SystemParam)]
+struct MaterialUsers<'w, 's> {
+    registry: Option<ResMut<'w, MaterialRegistry>>,
+    world: Option<ResMut<'w, World3D>>,
+    tiles: Query<'w, 's, &'static mut TileMaterial>,
+}
+
+fn create_texture_atlas(
+    mut atlas: AtlasSources,
+    registry: Option<Res<MaterialRegistry>>,
+    game_state: ResMut<State<GameState>>)
+    {
+    let registry = match registry {
+        Some(registry) => registry,
+        None => return,
+    };
+    if atlas.build_once_settled(&registry) {
+        next_game_state(game_state);
+    }
+}
+
+// With the asset server watching the assets folder, edited textures and material definitions are picked up while
+// the game runs. Once every texture has settled the atlas is rebuilt, and the renderer moves live tiles onto it.
+// Material ids come from the order of the definitions, so when they move the world and the drawn tiles are moved
+// along with them by name. Definitions that drop a material are rejected, since the world may still be made of it.
+fn reload_assets(
+    mut image_events: EventReader<AssetEvent<Image>>,
+    mut definition_events: EventReader<AssetEvent<MaterialDefinitions>>,
+    mut pending: Local<bool>,
+    state: Res<State<GameState>>,
+    mut atlas: AtlasSources,
+    users: MaterialUsers,
+    definitions: Res<Assets<MaterialDefinitions>>)
+    {
+    let images_changed = image_events.iter()
+        .any(|event| matches!(event, AssetEvent::Modified { handle } if atlas.texture_handles.vector.iter().flatten().any(|texture| texture == handle)));
+    let definitions_changed = definition_events.iter()
+        .any(|event| matches!(event, AssetEvent::Modified { handle } if *handle == atlas.texture_handles.definitions));
+    let MaterialUsers { registry, world, mut tiles } = users;
+    // The first load belongs to the loading screen.
+    let mut registry = match registry {
+        Some(registry) if *state.current() != InitalizeAssets => registry,
         _ => return,
-    }    
-}
\ No newline at end of file
+    };
+    if images_changed || definitions_changed {
+        atlas.report.errors.clear();
+        *pending = true;
+    }
+    if let Some(loaded) = definitions.get(&atlas.texture_handles.definitions).filter(|_| definitions_changed) {
+        let reloaded = MaterialRegistry::new(&loaded.materials);
+        match reloaded.remap_from(&registry) {
+            Ok(remap) => {
+                if remap.iter().enumerate().any(|(old, new)| new.0 as usize != old) {
+                    if let Some(mut world) = world {
+                        world.remap_materials(&remap);
+                    }
+                    for mut tile in tiles.iter_mut() {
+                        tile.material = remap[tile.material];
+                    }
+                }
+                atlas.texture_handles.remap_materials(&remap, reloaded.len());
+                *registry = reloaded;
+                atlas.texture_handles.vector = load_material_textures(&registry, &atlas.asset_server);
+            }
+            Err(name) => atlas.report.errors.push(format!(
+                "Kept the previous material definitions, {} no longer defines \"{}\"", MATERIALS_PATH, name
+            )),
+        }
+    }
+    if *pending && atlas.build_once_settled(&registry) {
+        *pending = false;
+    }
+}
+
+fn loading_screen(mut egui_context: ResMut<EguiContext>, report: Res<AssetLoadReport>) {
+    egui::CentralPanel::default().show(egui_context.ctx_mut(), |ui| {
+        ui.vertical_centered(|ui| {
+            ui.heading("Loading");
+            let progress = if report.total == 0 { 0.0 } else { report.loaded as f32 / report.total as f32 };
+            ui.add(egui::ProgressBar::new(progress).desired_width(300.0).text(format!("{} / {} files", report.loaded, report.total)));
+            for error in report.errors.iter() {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+        });
+    });
+}
+
+// Loading moves on to the main menu straight away, so whatever went wrong is listed there until dismissed,
+// along with anything that breaks when assets are reloaded in game.
+fn asset_errors_window(mut egui_context: ResMut<EguiContext>, mut report: ResMut<AssetLoadReport>) {
+    if report.errors.is_empty() {
+        return;
+    }
+    let mut open = true;
+    egui::Window::new("Missing assets").open(&mut open).show(egui_context.ctx_mut(), |ui| {
+        ui.label("Placeholders are shown in place of anything that could not be loaded.");
+        for error in report.errors.iter() {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+    });
+    if !open {
+        report.errors.clear();
+    }
+}
@@ -0,0 +1,156 @@
+use bevy::ecs::schedule::ShouldRun;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+use crate::actions::{Action, ActionInput};
+use crate::GameState::{self, Game};
+
+// Game ticks per real second at the slowest speed.
+pub const TICKS_PER_SECOND: f64 = 3.0;
+pub const SPEED_STEPS: [f64; 4] = [1.0, 2.0, 4.0, 8.0];
+// A slow frame catches up on at most this many ticks, the rest of the backlog is dropped instead of
+// making the next frame even slower.
+const MAX_TICKS_PER_FRAME: u32 = 10;
+
+// Simulation systems go in this stage, which runs once for every game tick that is due, after Update.
+#[derive(StageLabel)]
+pub struct SimulationStage;
+
+#[derive(Resource)]
+pub struct GameClock {
+    pub paused: bool,
+    // Index into SPEED_STEPS.
+    pub speed: usize,
+    // Real time clocks follow the wall clock, otherwise every frame is one tick, which is what headless runs want.
+    pub realtime: bool,
+    accumulator: f64,
+    pending: u32,
+    step_requested: bool,
+}
+impl Default for GameClock {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            speed: 0,
+            realtime: true,
+            accumulator: 0.0,
+            pending: 0,
+            step_requested: false,
+        }
+    }
+}
+impl GameClock {
+    pub fn multiplier(self: &Self) -> f64 {
+        SPEED_STEPS[self.speed.min(SPEED_STEPS.len() - 1)]
+    }
+    // Runs exactly one tick on the next frame, even while paused.
+    pub fn step(self: &mut Self) {
+        self.step_requested = true;
+    }
+    pub fn faster(self: &mut Self) {
+        self.speed = (self.speed + 1).min(SPEED_STEPS.len() - 1);
+    }
+    pub fn slower(self: &mut Self) {
+        self.speed = self.speed.saturating_sub(1);
+    }
+}
+
+// Number of game ticks simulated since the world was created, never goes backwards.
+#[derive(Resource, Default, Clone, Copy, Debug)]
+pub struct GameTick(pub u64);
+
+pub struct TickPlugin;
+
+impl Plugin for TickPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GameClock>()
+        .init_resource::<GameTick>()
+        .add_system_to_stage(CoreStage::First, advance_clock)
+        .add_stage_after(
+            CoreStage::Update,
+            SimulationStage,
+            SystemStage::parallel().with_run_criteria(run_due_ticks)
+        );
+    }
+}
+
+// Keyboard and on screen controls for the clock, only in the windowed game.
+pub struct TickControlsPlugin;
+
+impl Plugin for TickControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_update(Game)
+                .with_system(clock_input)
+                .with_system(clock_window)
+        );
+    }
+}
+
+fn advance_clock(time: Res<Time>, state: Res<State<GameState>>, mut clock: ResMut<GameClock>) {
+    if *state.current() != Game {
+        clock.accumulator = 0.0;
+        return;
+    }
+    let mut due = 0;
+    if !clock.paused {
+        if clock.realtime {
+            clock.accumulator += time.delta_seconds_f64() * TICKS_PER_SECOND * clock.multiplier();
+            due = clock.accumulator.floor() as u32;
+            clock.accumulator -= due as f64;
+        } else {
+            due = 1;
+        }
+    }
+    if clock.step_requested {
+        due = due.max(1);
+        clock.step_requested = false;
+    }
+    clock.pending = due.min(MAX_TICKS_PER_FRAME);
+}
+
+fn run_due_ticks(mut clock: ResMut<GameClock>, mut tick: ResMut<GameTick>) -> ShouldRun {
+    if clock.pending == 0 {
+        return ShouldRun::No;
+    }
+    clock.pending -= 1;
+    tick.0 += 1;
+    ShouldRun::YesAndCheckAgain
+}
+
+fn clock_input(mut actions: ActionInput, mut clock: ResMut<GameClock>) {
+    if actions.just_pressed(Action::Pause) {
+        clock.paused = !clock.paused;
+    }
+    if actions.just_pressed(Action::Step) {
+        clock.step();
+    }
+    if actions.just_pressed(Action::SpeedUp) {
+        clock.faster();
+    }
+    if actions.just_pressed(Action::SlowDown) {
+        clock.slower();
+    }
+}
+
+fn clock_window(mut egui_context: ResMut<EguiContext>, mut clock: ResMut<GameClock>, tick: Res<GameTick>) {
+    egui::Window::new("Time").resizable(false).show(egui_context.ctx_mut(), |ui| {
+        ui.label(format!("Tick {}", tick.0));
+        ui.horizontal(|ui| {
+            let pause_label = if clock.paused { "Resume" } else { "Pause" };
+            if ui.button(pause_label).clicked() {
+                clock.paused = !clock.paused;
+            }
+            if ui.add_enabled(clock.paused, egui::Button::new("Step")).clicked() {
+                clock.step();
+            }
+        });
+        ui.horizontal(|ui| {
+            for (index, multiplier) in SPEED_STEPS.iter().enumerate() {
+                if ui.selectable_label(clock.speed == index, format!("{}x", multiplier)).clicked() {
+                    clock.speed = index;
+                }
+            }
+        });
+    });
+}
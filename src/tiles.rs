@@ -1,8 +1,9 @@
 use std::ops::Range;
 
-use bevy::prelude::{Component, Entity};
-use bevy_ecs_tilemap::{prelude::TilemapSize, tiles::{TilePos, TileStorage}};
+use bevy::prelude::Component;
+use bevy_ecs_tilemap::{prelude::TilemapSize, tiles::TilePos};
 use itertools::{Itertools, Product};
+use serde::{Deserialize, Serialize};
 
 #[derive(Component, Clone, Copy)]
 pub struct GameTilemapSize{
@@ -62,7 +63,7 @@ impl<T, A, B, C> TupleFrom<(A, B, C)> for [T; 3]
     fn tuple_from(t: (A, B, C)) -> Self { [t.0.into(), t.1.into(), t.2.into()] }
 }
 
-#[derive(Component, Copy, Clone)]
+#[derive(Component, Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct GameTilePos {
     pub x: usize,
     pub y: usize,
@@ -103,7 +104,7 @@ impl From<GameTilePos> for TilePos{
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Game3DSize {
     pub x: usize,
     pub y: usize,
@@ -114,11 +115,3 @@ impl From<Game3DSize> for [usize; 3] {
         [value.x, value.y, value.z]
     }
 }
-pub trait Index2D {
-    fn get_2d(self: &Self, coord: (usize, usize)) -> Option<Entity>;
-}
-impl Index2D for TileStorage{
-    fn get_2d(self: &Self, coord: (usize, usize)) -> Option<Entity> {
-        self.get(&TilePos{x: coord.0 as u32, y: coord.1 as u32})
-    }
-}
\ No newline at end of file
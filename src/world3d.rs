@@ -0,0 +1,357 @@
+use std::collections::HashSet;
+
+use bevy::prelude::Resource;
+
+use crate::map_gen::height::Height;
+use crate::map_gen::{Voxel, VoxelMap};
+use crate::materials::MaterialId;
+use crate::tiles::{Game3DSize, GameTilePos};
+
+pub const CHUNK_SIZE: usize = 16;
+pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkPos {
+    pub x: usize,
+    pub y: usize,
+    pub z: usize,
+}
+
+// Most of the underground is a single rock type, so a chunk only stores every voxel once it stops being uniform,
+// and even then as indices into the handful of materials it actually contains.
+#[derive(Clone)]
+enum Chunk {
+    Uniform(Voxel),
+    Paletted {
+        palette: Vec<Voxel>,
+        indices: Vec<u8>,
+    },
+    Dense(Vec<Voxel>),
+}
+impl Chunk {
+    fn get(self: &Self, index: usize) -> Voxel {
+        match self {
+            Chunk::Uniform(voxel) => *voxel,
+            Chunk::Paletted { palette, indices } => palette[indices[index] as usize],
+            Chunk::Dense(voxels) => voxels[index],
+        }
+    }
+    fn set(self: &mut Self, index: usize, voxel: Voxel) {
+        match self {
+            Chunk::Uniform(current) => {
+                if *current != voxel {
+                    let mut indices = vec![0; CHUNK_VOLUME];
+                    indices[index] = 1;
+                    *self = Chunk::Paletted { palette: vec![*current, voxel], indices };
+                }
+            }
+            Chunk::Paletted { palette, indices } => {
+                let palette_index = match palette.iter().position(|entry| *entry == voxel) {
+                    Some(palette_index) => palette_index,
+                    None if palette.len() <= u8::MAX as usize => {
+                        palette.push(voxel);
+                        palette.len() - 1
+                    }
+                    None => {
+                        let mut voxels: Vec<Voxel> = indices.iter().map(|entry| palette[*entry as usize]).collect();
+                        voxels[index] = voxel;
+                        *self = Chunk::Dense(voxels);
+                        return;
+                    }
+                };
+                indices[index] = palette_index as u8;
+            }
+            Chunk::Dense(voxels) => voxels[index] = voxel,
+        }
+    }
+    fn from_voxels(voxels: Vec<Voxel>) -> Self {
+        let mut chunk = Chunk::Uniform(voxels[0]);
+        for (index, voxel) in voxels.into_iter().enumerate() {
+            chunk.set(index, voxel);
+        }
+        chunk
+    }
+    fn remap(self: &mut Self, remap: &[MaterialId]) {
+        let remap_voxel = |voxel: &mut Voxel| *voxel = voxel.map(|material| remap[material.0 as usize]);
+        match self {
+            Chunk::Uniform(voxel) => remap_voxel(voxel),
+            Chunk::Paletted { palette, .. } => palette.iter_mut().for_each(remap_voxel),
+            Chunk::Dense(voxels) => voxels.iter_mut().for_each(remap_voxel),
+        }
+    }
+    fn voxels(self: &Self) -> Vec<Voxel> {
+        (0..CHUNK_VOLUME).map(|index| self.get(index)).collect()
+    }
+}
+
+// The material of every tile in the world, stored independently of the entities used to render it.
+// Positions are GameTilePos, so z is an absolute height starting at the map's minimum height.
+#[derive(Resource, Clone)]
+pub struct World3D {
+    size: Game3DSize,
+    min_height: Height,
+    chunk_counts: [usize; 3],
+    chunks: Vec<Chunk>,
+    // Chunks written to since the renderer last looked, so it only redraws what actually changed.
+    dirty: HashSet<ChunkPos>,
+}
+impl World3D {
+    pub fn new(size: Game3DSize, min_height: Height, fill: Voxel) -> Self {
+        let chunk_counts = [
+            size.x.div_ceil(CHUNK_SIZE),
+            size.y.div_ceil(CHUNK_SIZE),
+            size.z.div_ceil(CHUNK_SIZE),
+        ];
+        Self {
+            size,
+            min_height,
+            chunk_counts,
+            chunks: vec![Chunk::Uniform(fill); chunk_counts[0] * chunk_counts[1] * chunk_counts[2]],
+            dirty: HashSet::new(),
+        }
+    }
+    pub fn from_voxels(voxels: &VoxelMap, min_height: Height) -> Self {
+        let (x, y, z) = voxels.dim();
+        let mut world = Self::new(Game3DSize { x, y, z }, min_height, None);
+        for chunk_z in 0..world.chunk_counts[2] {
+            for chunk_y in 0..world.chunk_counts[1] {
+                for chunk_x in 0..world.chunk_counts[0] {
+                    let chunk_pos = ChunkPos { x: chunk_x, y: chunk_y, z: chunk_z };
+                    let chunk_voxels = (0..CHUNK_VOLUME).map(|index| {
+                        let [x, y, z] = Self::local_to_array(chunk_pos, index);
+                        voxels.get([x, y, z]).copied().flatten().map(MaterialId::from)
+                    }).collect();
+                    let chunk_index = world.chunk_index(chunk_pos);
+                    world.chunks[chunk_index] = Chunk::from_voxels(chunk_voxels);
+                }
+            }
+        }
+        world
+    }
+    pub fn size(self: &Self) -> Game3DSize {
+        self.size
+    }
+    pub fn min_height(self: &Self) -> Height {
+        self.min_height
+    }
+    pub fn contains(self: &Self, position: GameTilePos) -> bool {
+        position.x < self.size.x
+            && position.y < self.size.y
+            && position.z >= self.min_height.value
+            && position.z - self.min_height.value < self.size.z
+    }
+    // Everything outside the world reads as open air.
+    pub fn get(self: &Self, position: GameTilePos) -> Voxel {
+        if !self.contains(position) {
+            return None;
+        }
+        let (chunk_pos, index) = self.locate(position);
+        self.chunks[self.chunk_index(chunk_pos)].get(index)
+    }
+    // Returns the voxel that was replaced, or None when the position is outside the world. The renderer redraws
+    // whatever this marks dirty.
+    pub fn set(self: &mut Self, position: GameTilePos, voxel: Voxel) -> Option<Voxel> {
+        if !self.contains(position) {
+            return None;
+        }
+        let (chunk_pos, index) = self.locate(position);
+        let chunk_index = self.chunk_index(chunk_pos);
+        let previous = self.chunks[chunk_index].get(index);
+        if previous != voxel {
+            self.chunks[chunk_index].set(index, voxel);
+            self.dirty.insert(chunk_pos);
+        }
+        Some(previous)
+    }
+    pub fn is_solid(self: &Self, position: GameTilePos) -> bool {
+        self.get(position).is_some()
+    }
+    // The highest tile in a column that is not open air, which is the heightmap surface unless water covers it.
+    pub fn column_top(self: &Self, coordinate: (usize, usize)) -> Height {
+        let (x, y) = coordinate;
+        let min = self.min_height.value;
+        let top = (min..min + self.size.z).rev().find(|z| self.is_solid([x, y, *z].into())).unwrap_or(min);
+        Height { value: top }
+    }
+    pub fn has_dirty_chunks(self: &Self) -> bool {
+        !self.dirty.is_empty()
+    }
+    pub fn take_dirty_chunks(self: &mut Self) -> Vec<ChunkPos> {
+        self.dirty.drain().collect()
+    }
+    // Moves every voxel onto the material ids of a rebuilt MaterialRegistry, indexed by the old id. Only the ids
+    // change, so nothing is redrawn.
+    pub fn remap_materials(self: &mut Self, remap: &[MaterialId]) {
+        for chunk in self.chunks.iter_mut() {
+            chunk.remap(remap);
+        }
+    }
+    // Every chunk position, in the order the chunks are stored.
+    pub fn chunk_positions(self: &Self) -> impl Iterator<Item = ChunkPos> {
+        let [x_count, y_count, z_count] = self.chunk_counts;
+        (0..z_count).flat_map(move |z| (0..y_count).flat_map(move |y| (0..x_count).map(move |x| ChunkPos { x, y, z })))
+    }
+    // The voxel filling a chunk, when the whole chunk is a single material.
+    pub fn uniform_voxel(self: &Self, chunk_pos: ChunkPos) -> Option<Voxel> {
+        match self.chunks[self.chunk_index(chunk_pos)] {
+            Chunk::Uniform(voxel) => Some(voxel),
+            _ => None,
+        }
+    }
+    // Every voxel of a chunk, ordered x first, then y, then z.
+    pub fn chunk_voxels(self: &Self, chunk_pos: ChunkPos) -> Vec<Voxel> {
+        self.chunks[self.chunk_index(chunk_pos)].voxels()
+    }
+    pub fn set_chunk_voxels(self: &mut Self, chunk_pos: ChunkPos, voxels: Vec<Voxel>) {
+        let chunk_index = self.chunk_index(chunk_pos);
+        self.chunks[chunk_index] = Chunk::from_voxels(voxels);
+        self.dirty.insert(chunk_pos);
+    }
+
+    fn locate(self: &Self, position: GameTilePos) -> (ChunkPos, usize) {
+        let z = position.z - self.min_height.value;
+        let chunk_pos = ChunkPos { x: position.x / CHUNK_SIZE, y: position.y / CHUNK_SIZE, z: z / CHUNK_SIZE };
+        let (local_x, local_y, local_z) = (position.x % CHUNK_SIZE, position.y % CHUNK_SIZE, z % CHUNK_SIZE);
+        (chunk_pos, (local_z * CHUNK_SIZE + local_y) * CHUNK_SIZE + local_x)
+    }
+    fn chunk_index(self: &Self, chunk_pos: ChunkPos) -> usize {
+        (chunk_pos.z * self.chunk_counts[1] + chunk_pos.y) * self.chunk_counts[0] + chunk_pos.x
+    }
+    fn local_to_array(chunk_pos: ChunkPos, index: usize) -> [usize; 3] {
+        let local_x = index % CHUNK_SIZE;
+        let local_y = (index / CHUNK_SIZE) % CHUNK_SIZE;
+        let local_z = index / (CHUNK_SIZE * CHUNK_SIZE);
+        [chunk_pos.x * CHUNK_SIZE + local_x, chunk_pos.y * CHUNK_SIZE + local_y, chunk_pos.z * CHUNK_SIZE + local_z]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN_HEIGHT: usize = 10;
+
+    fn world() -> World3D {
+        World3D::new(Game3DSize { x: 40, y: 20, z: 20 }, Height { value: MIN_HEIGHT }, Some(MaterialId(2)))
+    }
+
+    fn position(x: usize, y: usize, z: usize) -> GameTilePos {
+        GameTilePos { x, y, z: MIN_HEIGHT + z }
+    }
+
+    // The n-th tile of the first chunk, in the order chunks store them.
+    fn nth_in_first_chunk(index: usize) -> GameTilePos {
+        position(index % CHUNK_SIZE, (index / CHUNK_SIZE) % CHUNK_SIZE, index / (CHUNK_SIZE * CHUNK_SIZE))
+    }
+
+    fn first_chunk(world: &World3D) -> &Chunk {
+        &world.chunks[0]
+    }
+
+    #[test]
+    fn new_world_is_uniform() {
+        let world = world();
+        assert!(world.chunks.iter().all(|chunk| matches!(chunk, Chunk::Uniform(Some(MaterialId(2))))));
+        assert_eq!(world.get(position(39, 19, 19)), Some(MaterialId(2)));
+        assert!(!world.has_dirty_chunks());
+    }
+
+    #[test]
+    fn outside_the_world_is_air() {
+        let mut world = world();
+        assert_eq!(world.get(position(40, 0, 0)), None);
+        assert_eq!(world.get(GameTilePos { x: 0, y: 0, z: MIN_HEIGHT - 1 }), None);
+        assert_eq!(world.get(position(0, 0, 20)), None);
+        assert_eq!(world.set(position(0, 20, 0), Some(MaterialId(3))), None);
+        assert!(!world.has_dirty_chunks());
+    }
+
+    #[test]
+    fn setting_the_same_voxel_keeps_the_chunk_uniform() {
+        let mut world = world();
+        assert_eq!(world.set(position(1, 2, 3), Some(MaterialId(2))), Some(Some(MaterialId(2))));
+        assert!(matches!(first_chunk(&world), Chunk::Uniform(_)));
+        assert!(!world.has_dirty_chunks());
+    }
+
+    #[test]
+    fn uniform_becomes_paletted() {
+        let mut world = world();
+        assert_eq!(world.set(position(1, 2, 3), None), Some(Some(MaterialId(2))));
+        assert_eq!(world.set(position(4, 5, 6), Some(MaterialId(7))), Some(Some(MaterialId(2))));
+        match first_chunk(&world) {
+            Chunk::Paletted { palette, .. } => assert_eq!(palette.len(), 3),
+            _ => panic!("expected a paletted chunk"),
+        }
+        assert_eq!(world.get(position(1, 2, 3)), None);
+        assert_eq!(world.get(position(4, 5, 6)), Some(MaterialId(7)));
+        assert_eq!(world.get(position(0, 0, 0)), Some(MaterialId(2)));
+        assert_eq!(world.get(position(15, 15, 15)), Some(MaterialId(2)));
+        // Reusing a material already in the palette does not grow it.
+        world.set(position(8, 8, 8), Some(MaterialId(7)));
+        match first_chunk(&world) {
+            Chunk::Paletted { palette, .. } => assert_eq!(palette.len(), 3),
+            _ => panic!("expected a paletted chunk"),
+        }
+    }
+
+    #[test]
+    fn full_palette_becomes_dense() {
+        let mut world = world();
+        // The filling material plus 300 more is more than a palette can index.
+        for index in 0..300 {
+            world.set(nth_in_first_chunk(index), Some(MaterialId(100 + index as u16)));
+            let expect_dense = index >= u8::MAX as usize;
+            assert_eq!(matches!(first_chunk(&world), Chunk::Dense(_)), expect_dense, "after {} materials", index + 1);
+        }
+        for index in 0..300 {
+            assert_eq!(world.get(nth_in_first_chunk(index)), Some(MaterialId(100 + index as u16)));
+        }
+        assert_eq!(world.get(nth_in_first_chunk(300)), Some(MaterialId(2)));
+        world.set(nth_in_first_chunk(0), None);
+        assert_eq!(world.get(nth_in_first_chunk(0)), None);
+    }
+
+    #[test]
+    fn chunk_voxels_round_trip() {
+        let mut world = world();
+        world.set(position(3, 3, 3), Some(MaterialId(9)));
+        let chunk_pos = ChunkPos { x: 0, y: 0, z: 0 };
+        let voxels = world.chunk_voxels(chunk_pos);
+        let mut copy = World3D::new(world.size(), world.min_height(), None);
+        copy.set_chunk_voxels(chunk_pos, voxels.clone());
+        assert_eq!(copy.chunk_voxels(chunk_pos), voxels);
+        assert_eq!(copy.get(position(3, 3, 3)), Some(MaterialId(9)));
+        // A chunk that turns out to hold a single material is stored as one.
+        copy.set_chunk_voxels(chunk_pos, vec![None; CHUNK_VOLUME]);
+        assert_eq!(copy.uniform_voxel(chunk_pos), Some(None));
+    }
+
+    #[test]
+    fn writes_mark_their_chunk_dirty() {
+        let mut world = world();
+        world.set(position(1, 1, 1), None);
+        world.set(position(2, 1, 1), None);
+        world.set(position(17, 1, 17), None);
+        let mut dirty = world.take_dirty_chunks();
+        dirty.sort_by_key(|chunk_pos| (chunk_pos.x, chunk_pos.y, chunk_pos.z));
+        assert_eq!(dirty, vec![ChunkPos { x: 0, y: 0, z: 0 }, ChunkPos { x: 1, y: 0, z: 1 }]);
+        assert!(!world.has_dirty_chunks());
+        // Writing what is already there changes nothing to redraw.
+        world.set(position(1, 1, 1), None);
+        assert!(!world.has_dirty_chunks());
+    }
+
+    #[test]
+    fn remapping_materials_keeps_air() {
+        let mut world = world();
+        world.set(position(1, 1, 1), None);
+        world.set(position(2, 1, 1), Some(MaterialId(1)));
+        let remap: Vec<MaterialId> = (0..3).map(|id| MaterialId(2 - id)).collect();
+        world.remap_materials(&remap);
+        assert_eq!(world.get(position(0, 0, 0)), Some(MaterialId(0)));
+        assert_eq!(world.get(position(1, 1, 1)), None);
+        assert_eq!(world.get(position(2, 1, 1)), Some(MaterialId(1)));
+        assert_eq!(world.get(position(39, 19, 19)), Some(MaterialId(0)));
+    }
+}